@@ -32,6 +32,13 @@ let
         default = "error";
         description = "How to handle conflicts";
       };
+
+      push_order = mkOption {
+        type = types.bool;
+        default = false;
+        description =
+          "Whether the line order of the file is pushed to Nextcloud, instead of the other way around";
+      };
     };
   };
 
@@ -59,6 +66,73 @@ let
           "The path to the bookmarks file. Only used for file storages.";
       };
 
+      format = mkOption {
+        type = types.nullOr (types.either
+          (types.enum [ "quickmarks" "urls" "vimb" "tsv" ])
+          (types.submodule formatOpts));
+        default = null;
+        description =
+          "The layout of the lines of the bookmarks file, a preset or the fields and separator. Defaults to quickmarks. Only used for file storages.";
+      };
+
+      sort = mkOption {
+        type =
+          types.nullOr (types.enum [ "path" "url" "folder" "lastmodified" ]);
+        default = null;
+        description =
+          "Sort the lines of the bookmarks file, instead of following the order of the other side. Only used for file storages.";
+      };
+
+      group_folders = mkOption {
+        type = types.nullOr types.bool;
+        default = null;
+        description =
+          "Whether top level folders are separated by a blank line. Only used for file storages.";
+      };
+
+      read_filter = mkOption {
+        type = types.nullOr (types.submodule commandOpts);
+        default = null;
+        description =
+          "Command which turns the contents of the bookmarks file into the bookmarks, e.g. to decrypt it. Only used for file storages.";
+      };
+
+      write_filter = mkOption {
+        type = types.nullOr (types.submodule commandOpts);
+        default = null;
+        description =
+          "Command which turns the bookmarks into the contents of the bookmarks file, e.g. to encrypt it. Only used for file storages.";
+      };
+
+      backup_path = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        example = "/home/john/.local/share/nc-bookmark-sync/backups";
+        description =
+          "Directory where the bookmarks file is backed up before it is changed. Only used for file storages.";
+      };
+
+      backup_count = mkOption {
+        type = types.nullOr types.int;
+        default = null;
+        description =
+          "How many backups of the bookmarks file are kept. Defaults to 10.";
+      };
+
+      malformed_lines = mkOption {
+        type = types.nullOr (types.enum [ "skip" "abort" ]);
+        default = null;
+        description =
+          "Whether malformed lines in the bookmarks file are skipped with a warning, or abort the sync. Defaults to skip. Only used for file storages.";
+      };
+
+      auth = mkOption {
+        type = types.nullOr (types.enum [ "basic" "bearer" "header" ]);
+        default = null;
+        description =
+          "How to authenticate with Nextcloud. Defaults to basic, with the username and password.";
+      };
+
       username = mkOption {
         type = types.nullOr (types.submodule commandOpts);
         default = null;
@@ -70,6 +144,97 @@ let
         default = null;
         description = "Nextcloud password";
       };
+
+      token = mkOption {
+        type = types.nullOr (types.submodule commandOpts);
+        default = null;
+        description = "Token for bearer authentication, or value for header authentication";
+      };
+
+      header = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        example = "X-Auth-Token";
+        description = "Name of the header for header authentication";
+      };
+
+      login = mkOption {
+        type = types.nullOr (types.submodule loginOpts);
+        default = null;
+        description =
+          "Where the login command saves the credentials. Only used for Nextcloud storages.";
+      };
+
+      ca_file = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description =
+          "PEM file with extra certificate authorities to trust. Only used for Nextcloud storages.";
+      };
+
+      client_certificate = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description =
+          "PEM file with a client certificate for mutual TLS. Only used for Nextcloud storages.";
+      };
+
+      client_key = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description =
+          "PEM file with the PKCS #8 private key of the client certificate.";
+      };
+
+      certificate_fingerprint = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description =
+          "SHA-256 fingerprint of the only server certificate to accept.";
+      };
+
+      danger_accept_invalid_certs = mkOption {
+        type = types.nullOr types.bool;
+        default = null;
+        description =
+          "Accept any server certificate. Only meant for test setups.";
+      };
+
+      proxy = mkOption {
+        type = types.nullOr (types.submodule proxyOpts);
+        default = null;
+        description =
+          "Proxy for Nextcloud requests, which takes precedence over the environment variables.";
+      };
+
+      concurrency = mkOption {
+        type = types.nullOr types.ints.positive;
+        default = null;
+        description =
+          "How many requests to Nextcloud may run at the same time. Only used for Nextcloud storages.";
+      };
+
+      import_threshold = mkOption {
+        type = types.nullOr types.ints.unsigned;
+        default = null;
+        description =
+          "Above this many new bookmarks, they are uploaded with one import per folder. Only used for Nextcloud storages.";
+      };
+
+      root_folder = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        example = "Browsers/qutebrowser";
+        description =
+          "Nextcloud folder to sync instead of the whole account. Only used for Nextcloud storages.";
+      };
+
+      remove_empty_folders = mkOption {
+        type = types.nullOr types.bool;
+        default = null;
+        description =
+          "Delete Nextcloud folders which became empty during the sync. Only used for Nextcloud storages.";
+      };
     };
   };
 
@@ -84,21 +249,107 @@ let
     };
   };
 
+  writerOpts = { config, ... }: {
+    options = {
+      store = mkOption {
+        type = types.nullOr (types.listOf types.str);
+        default = null;
+        example = [ "command" "pass" "insert" "--multiline" "cloud.example.com" ];
+        description =
+          "This command receives the value on its standard input. The first entry of the list should always be 'command'.";
+      };
+
+      file = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = "The value is written to this file.";
+      };
+    };
+  };
+
+  formatOpts = { config, ... }: {
+    options = {
+      fields = mkOption {
+        type = types.listOf types.str;
+        example = [ "url" "name" "tags?" ];
+        description =
+          "The fields of a line in order, with name and url. Fields ending in ? may be left out at the end of a line.";
+      };
+
+      separator = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description =
+          "The text between the fields. Without it, fields are separated by whitespace.";
+      };
+    };
+  };
+
+  proxyOpts = { config, ... }: {
+    options = {
+      url = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        example = "http://proxy.example.com:3128";
+        description = "Proxy url. Without it, Nextcloud is connected to directly.";
+      };
+
+      username = mkOption {
+        type = types.nullOr (types.submodule commandOpts);
+        default = null;
+        description = "Proxy username";
+      };
+
+      password = mkOption {
+        type = types.nullOr (types.submodule commandOpts);
+        default = null;
+        description = "Proxy password";
+      };
+
+      no_proxy = mkOption {
+        type = types.listOf types.str;
+        default = [ ];
+        description = "Hosts which are connected to directly";
+      };
+    };
+  };
+
+  loginOpts = { config, ... }: {
+    options = {
+      username = mkOption {
+        type = types.nullOr (types.submodule writerOpts);
+        default = null;
+        description = "Where to save the Nextcloud username";
+      };
+
+      password = mkOption {
+        type = types.submodule writerOpts;
+        description = "Where to save the app password";
+      };
+    };
+  };
+
   mkAttrSet = pair: {
     pair."${pair.name}" = {
       a = "${pair.name}_local";
       b = "${pair.name}_remote";
       conflict_resolution = pair.conflict_resolution;
+      push_order = pair.push_order;
     };
 
     storage = {
-      "${pair.name}_local" = filterAttrs (n: v: v != null) pair.a;
+      "${pair.name}_local" = filterAttrsRecursive (n: v: v != null) pair.a;
 
-      "${pair.name}_remote" = filterAttrs (n: v: v != null) pair.b;
+      "${pair.name}_remote" = filterAttrsRecursive (n: v: v != null) pair.b;
     };
   };
 
-  toml = mkMerge ([{ general.status_path = cfg.status_path; }]
+  toml = mkMerge ([{
+    general = {
+      status_path = cfg.status_path;
+      lock = cfg.lock;
+    };
+  }]
     ++ (mapAttrsToList (_: mkAttrSet) cfg.pairs) ++ [ cfg.extraConfig ]);
 
 in {
@@ -111,6 +362,13 @@ in {
       description = "Status path";
     };
 
+    lock = mkOption {
+      type = types.enum [ "wait" "fail" ];
+      default = "wait";
+      description =
+        "Whether to wait for, or fail on another process syncing the same pair or file";
+    };
+
     pairs = mkOption {
       type = types.attrsOf (types.submodule pairCfg);
       default = { };
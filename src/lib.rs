@@ -1,3 +1,4 @@
+use fs4::fs_std::FileExt;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
@@ -11,9 +12,71 @@ type UnixTime = u64;
 
 pub fn main() {
     let args: Vec<String> = env::args().collect();
-    let configfile: PathBuf = if args.len() > 1 {
+
+    match args.get(1).map(String::as_str) {
+        Some("login") => {
+            let storage = args
+                .get(2)
+                .expect("Usage: nc-bookmark-sync login <storage> [config]");
+            let config = read_config(args.get(3));
+            login(&config, storage).unwrap();
+        }
+        Some("doctor") => doctor(&read_config(args.get(2))).unwrap(),
+        Some("backups") => {
+            let usage = "Usage: nc-bookmark-sync backups list <storage> [config]\n       \
+                nc-bookmark-sync backups restore <storage> <backup|latest> [config]";
+            let storage = args.get(3).expect(usage);
+            match args.get(2).map(String::as_str) {
+                Some("list") => list_backups(&read_config(args.get(4)), storage).unwrap(),
+                Some("restore") => {
+                    let backup = args.get(4).expect(usage);
+                    restore_backup(&read_config(args.get(5)), storage, backup).unwrap();
+                }
+                _ => panic!("{}", usage),
+            }
+        }
+        Some("share") => {
+            let usage = "Usage: nc-bookmark-sync share <storage> <path> [config]";
+            let storage = args.get(2).expect(usage);
+            let path = args.get(3).expect(usage);
+            let config = read_config(args.get(4));
+            share(&config, storage, path).unwrap();
+        }
+        _ => sync(&read_config(args.get(1))),
+    }
+}
+
+/// Prints what the server of every Nextcloud storage supports
+fn doctor(config: &Config) -> Result<(), Error> {
+    let mut names: Vec<&String> = config.storage.keys().collect();
+    names.sort();
+
+    for name in names {
+        if let Storage::Nextcloud(mut nc_storage) = Storage::from_config(&config.storage[name])? {
+            nc_storage.probe()?;
+            let capabilities = &nc_storage.capabilities;
+            let supported = |yes: Option<bool>| if yes == Some(true) { "yes" } else { "no" };
+
+            println!("{} ({})", name, nc_storage.url);
+            println!("  folder hashes    {}", supported(capabilities.hash));
+            println!("  folder children  {}", supported(capabilities.children));
+            println!("  child order      {}", supported(capabilities.childorder));
+            println!(
+                "  client lock      {}",
+                supported(Some(nc_storage.probe_lock()?))
+            );
+            println!("  trash bin        {}", supported(capabilities.trash));
+            println!("  shared folders   {}", supported(capabilities.shared));
+        }
+    }
+
+    Ok(())
+}
+
+fn read_config(path: Option<&String>) -> Config {
+    let configfile: PathBuf = if let Some(path) = path {
         let mut configdir = env::current_dir().unwrap();
-        configdir.push(&args[1]);
+        configdir.push(path);
         configdir
     } else {
         let mut configdir = dirs::config_dir().unwrap();
@@ -26,30 +89,123 @@ pub fn main() {
     }
 
     let contents = fs::read_to_string(configfile).unwrap();
-    let config: Config = toml::from_str(&contents).unwrap();
+    toml::from_str(&contents).unwrap()
+}
+
+/// Obtains an app password for a Nextcloud storage and saves it with the configured writers
+fn login(config: &Config, name: &str) -> Result<(), Error> {
+    let storage = config
+        .storage
+        .get(name)
+        .ok_or_else(|| Error::StorageNotFound(name.to_owned()))?;
+    let login = storage
+        .login
+        .as_ref()
+        .ok_or(Error::MissingConfig("login"))?;
+    let url = storage.url.as_ref().ok_or(Error::MissingConfig("url"))?;
+
+    let client = NextcloudStorage::client(storage)?;
+    let credentials = NextcloudStorage::login_flow(&client, url)?;
+
+    if let Some(username) = &login.username {
+        username.write(&credentials.login_name)?;
+    }
+    login.password.write(&credentials.app_password)?;
+
+    println!("Saved the app password for `{}`", name);
+    Ok(())
+}
+
+/// Prints the public link of a Nextcloud folder, which is published if it was not yet
+fn share(config: &Config, name: &str, path: &str) -> Result<(), Error> {
+    let storage = config
+        .storage
+        .get(name)
+        .ok_or_else(|| Error::StorageNotFound(name.to_owned()))?;
+
+    match Storage::from_config(storage)? {
+        Storage::Nextcloud(mut nc_storage) => {
+            println!("{}", nc_storage.public_link(path)?);
+            Ok(())
+        }
+        Storage::File(_) => Err(Error::MissingConfig("url")),
+    }
+}
+
+fn file_storage(config: &Config, name: &str) -> Result<FileStorage, Error> {
+    let storage = config
+        .storage
+        .get(name)
+        .ok_or_else(|| Error::StorageNotFound(name.to_owned()))?;
+
+    match Storage::from_config(storage)? {
+        Storage::File(fs_storage) => Ok(fs_storage),
+        Storage::Nextcloud(_) => Err(Error::MissingConfig("path")),
+    }
+}
+
+/// Prints the backups of a file storage, the newest first
+fn list_backups(config: &Config, name: &str) -> Result<(), Error> {
+    for backup in file_storage(config, name)?.backups()?.iter().rev() {
+        println!("{}", backup);
+    }
+    Ok(())
+}
+
+/// Replaces the file of a file storage by one of its backups
+fn restore_backup(config: &Config, name: &str, backup: &str) -> Result<(), Error> {
+    let fs_storage = file_storage(config, name)?;
+    let backups = fs_storage.backups()?;
+    let backup = match backup {
+        "latest" => backups.last(),
+        _ => backups.iter().find(|name| name.as_str() == backup),
+    }
+    .ok_or_else(|| Error::BackupNotFound(backup.to_owned()))?;
 
+    fs_storage.restore(backup)?;
+    println!("Restored `{}` from `{}`", fs_storage.path, backup);
+    Ok(())
+}
+
+fn sync(config: &Config) {
     for (name, pair) in config.pair.iter() {
         let storage_a = config
             .storage
             .get(&pair.a)
-            .ok_or(Error::StorageNotFound("a"))
+            .ok_or_else(|| Error::StorageNotFound(pair.a.clone()))
             .unwrap();
         let storage_b = config
             .storage
             .get(&pair.b)
-            .ok_or(Error::StorageNotFound("b"))
+            .ok_or_else(|| Error::StorageNotFound(pair.b.clone()))
             .unwrap();
 
-        let state_file = config.general.status_path.clone() + "/" + &name;
-
-        Pair::new(state_file, pair, storage_a, storage_b)
-            .unwrap()
-            .run()
-            .unwrap();
+        let state_file = config.general.status_path.clone() + "/" + name;
+
+        // A file which is edited during the sync is listed again
+        let mut attempts = 1;
+        loop {
+            let result = Pair::new(
+                state_file.clone(),
+                config.general.lock,
+                pair,
+                storage_a,
+                storage_b,
+            )
+            .and_then(|mut pair| pair.run());
+            match result {
+                Err(Error::FileChanged(path)) if attempts < 3 => {
+                    eprintln!("`{}` changed during the sync, trying again", path);
+                    attempts += 1;
+                }
+                result => break result.unwrap(),
+            }
+        }
     }
 }
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
     #[error("Missing config entry `{0}`")]
     MissingConfig(&'static str),
@@ -60,18 +216,56 @@ enum Error {
     #[error("Request error: {0}")]
     Reqwest(reqwest::Error),
     #[error("Storage `{0}` not found")]
-    StorageNotFound(&'static str),
+    StorageNotFound(String),
     #[error("Json print/parse error: {0}")]
     SerdeError(serde_json::Error),
     #[error("Time error: {0}")]
     TimeError(std::time::SystemTimeError),
     #[error("Sync conflict in storage `{0}`")]
     Conflict(String),
+    #[error("Folder `{0}` not found")]
+    FolderNotFound(String),
+    #[error("Command `{0}` failed")]
+    CommandFailed(String),
+    #[error("Login was not completed in time")]
+    LoginTimeout,
+    #[error("TLS configuration error: {0}")]
+    Tls(String),
+    #[error("Malformed line {1} in `{0}`: {2}")]
+    MalformedLine(String, usize, String),
+    #[error("File `{0}` changed during the sync")]
+    FileChanged(String),
+    #[error("Backup `{0}` not found")]
+    BackupNotFound(String),
+    #[error("`{0}` is locked by another process")]
+    Locked(String),
+    #[error("Bookmark `{0}` can not be written to `{1}`: {2}")]
+    Unwritable(String, String, String),
+    #[error(
+        "{} operations failed: {}",
+        .0.len(),
+        .0.iter().map(|error| error.to_string()).collect::<Vec<_>>().join("; ")
+    )]
+    Operations(Vec<Error>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct GeneralConfig {
     status_path: String,
+    /// What to do when another process syncs the same pair or file
+    #[serde(default)]
+    lock: LockMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+enum LockMode {
+    /// Wait until the other process is done
+    #[default]
+    #[serde(rename = "wait")]
+    Wait,
+    /// Stop with an error
+    #[serde(rename = "fail")]
+    Fail,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -80,10 +274,14 @@ struct PairConfig {
     b: String,
     #[serde(default)]
     conflict_resolution: ConflictResolution,
+    /// Whether the line order of the file is pushed to the child order of Nextcloud folders
+    #[serde(default)]
+    push_order: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 enum ConflictResolution {
+    #[default]
     #[serde(rename = "error")]
     Error,
     #[serde(rename = "a wins")]
@@ -92,20 +290,117 @@ enum ConflictResolution {
     BWins,
 }
 
-impl Default for ConflictResolution {
-    fn default() -> ConflictResolution {
-        ConflictResolution::Error
-    }
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 struct StorageConfig {
     #[serde(rename = "type")]
     _type: StorageType,
     url: Option<String>,
     path: Option<String>,
+    #[serde(default)]
+    auth: AuthMethod,
+    username: Option<Command>,
+    password: Option<Command>,
+    /// The bearer token or header value
+    token: Option<Command>,
+    /// The name of the header for `header` authentication
+    header: Option<String>,
+    #[serde(default)]
+    remove_empty_folders: bool,
+    login: Option<LoginConfig>,
+    /// PEM file with extra certificate authorities to trust
+    ca_file: Option<String>,
+    /// PEM file with the client certificate for mutual TLS
+    client_certificate: Option<String>,
+    /// PEM file with the PKCS #8 private key of the client certificate
+    client_key: Option<String>,
+    /// SHA-256 fingerprint of the only server certificate to accept
+    certificate_fingerprint: Option<String>,
+    /// Accept any server certificate, only meant for test setups
+    #[serde(default)]
+    danger_accept_invalid_certs: bool,
+    proxy: Option<ProxyConfig>,
+    /// How many requests to Nextcloud may run at the same time
+    concurrency: Option<usize>,
+    /// Above this many new bookmarks, they are uploaded with the import endpoint
+    import_threshold: Option<usize>,
+    /// The path of the Nextcloud folder to sync, instead of the whole account
+    root_folder: Option<String>,
+    /// What to do with lines of the file which are no bookmark, comment or blank line
+    #[serde(default)]
+    malformed_lines: MalformedLines,
+    /// The directory where the file is backed up before it is overwritten
+    backup_path: Option<String>,
+    /// How many backups of the file are kept
+    backup_count: Option<usize>,
+    /// The layout of the lines of the file
+    format: Option<FormatConfig>,
+    /// The order of the lines of the file, instead of the order of the other side
+    sort: Option<SortOrder>,
+    /// Whether top level folders are separated by blank lines
+    #[serde(default)]
+    group_folders: bool,
+    /// Receives the contents of the file on its standard input, and writes the bookmarks
+    read_filter: Option<Command>,
+    /// Receives the bookmarks on its standard input, and writes the contents of the file
+    write_filter: Option<Command>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum SortOrder {
+    #[serde(rename = "path")]
+    Path,
+    #[serde(rename = "url")]
+    Url,
+    /// By folder, and then by title within the folder
+    #[serde(rename = "folder")]
+    Folder,
+    /// The least recently modified first
+    #[serde(rename = "lastmodified")]
+    LastModified,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+enum MalformedLines {
+    /// Warn about the line and leave it as it is
+    #[default]
+    #[serde(rename = "skip")]
+    Skip,
+    /// Stop before anything is synced
+    #[serde(rename = "abort")]
+    Abort,
+}
+
+/// The proxy of a storage, which takes precedence over the proxy environment variables
+#[derive(Serialize, Deserialize, Debug)]
+struct ProxyConfig {
+    /// The http, https or socks5 url of the proxy, or none to connect directly
+    url: Option<String>,
     username: Option<Command>,
     password: Option<Command>,
+    /// Hosts which are connected to directly
+    #[serde(default)]
+    no_proxy: Vec<String>,
+}
+
+/// Where `login` saves the credentials it obtained
+#[derive(Serialize, Deserialize, Debug)]
+struct LoginConfig {
+    username: Option<Writer>,
+    password: Writer,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+enum AuthMethod {
+    /// The username and password
+    #[default]
+    #[serde(rename = "basic")]
+    Basic,
+    /// An `Authorization: Bearer` header with the token
+    #[serde(rename = "bearer")]
+    Bearer,
+    /// A custom header with the token as value
+    #[serde(rename = "header")]
+    Header,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -116,22 +411,16 @@ enum StorageType {
     File,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Command {
     fetch: Vec<String>,
 }
 
 impl Command {
     pub fn value(&self) -> Result<String, Error> {
-        let sh = if cfg!(target_os = "windows") {
-            std::process::Command::new(&self.fetch[1])
-                .args(&self.fetch[2..])
-                .output()
-        } else {
-            std::process::Command::new(&self.fetch[1])
-                .args(&self.fetch[2..])
-                .output()
-        };
+        let sh = std::process::Command::new(&self.fetch[1])
+            .args(&self.fetch[2..])
+            .output();
 
         let output = sh.map_err(Error::IOError)?;
 
@@ -139,6 +428,82 @@ impl Command {
 
         Ok(output.trim_end().to_string())
     }
+
+    /// Pipes the input through the command, and returns what it writes to its standard output
+    pub fn filter(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut child = std::process::Command::new(&self.fetch[1])
+            .args(&self.fetch[2..])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .map_err(Error::IOError)?;
+
+        // The input is written by another thread, so that a large output cannot block it
+        let mut stdin = child.stdin.take().unwrap();
+        let (written, output) = std::thread::scope(|scope| {
+            let writer = scope.spawn(move || stdin.write_all(input));
+            let output = child.wait_with_output();
+            (writer.join().unwrap(), output)
+        });
+
+        let output = output.map_err(Error::IOError)?;
+        if !output.status.success() {
+            return Err(Error::CommandFailed(self.fetch[1..].join(" ")));
+        }
+        written.map_err(Error::IOError)?;
+        Ok(output.stdout)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Writer {
+    /// A command which receives the value on its standard input
+    #[serde(rename = "store")]
+    Store(Vec<String>),
+    #[serde(rename = "file")]
+    File(String),
+}
+
+impl Writer {
+    pub fn write(&self, value: &str) -> Result<(), Error> {
+        match self {
+            Writer::Store(store) => {
+                let mut child = std::process::Command::new(&store[1])
+                    .args(&store[2..])
+                    .stdin(std::process::Stdio::piped())
+                    .spawn()
+                    .map_err(Error::IOError)?;
+
+                if let Some(stdin) = child.stdin.as_mut() {
+                    stdin
+                        .write_all((value.to_owned() + "\n").as_bytes())
+                        .map_err(Error::IOError)?;
+                }
+
+                let status = child.wait().map_err(Error::IOError)?;
+                if !status.success() {
+                    return Err(Error::CommandFailed(store[1..].join(" ")));
+                }
+                Ok(())
+            }
+            Writer::File(path) => {
+                let path = std::path::Path::new(path);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).map_err(Error::IOError)?;
+                }
+
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create(true).truncate(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+                let mut f = options.open(path).map_err(Error::IOError)?;
+                f.write_all((value.to_owned() + "\n").as_bytes())
+                    .map_err(Error::IOError)?;
+                Ok(())
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -172,6 +537,48 @@ struct Snapshot {
     #[allow(unused_variables, dead_code)]
     at: UnixTime,
     bookmarks: Vec<SBookmark>,
+    revision_a: Option<String>,
+    revision_b: Option<String>,
+    root_a: Option<i32>,
+    root_b: Option<i32>,
+}
+
+impl Snapshot {
+    /// The bookmarks of a side which is not listed, because it did not change since the snapshot
+    fn as_bookmarks(&self) -> Vec<Bookmark> {
+        self.bookmarks
+            .iter()
+            .map(|bookmark| Bookmark {
+                id: 0,
+                name: bookmark.name.clone(),
+                url: bookmark.url.clone(),
+                lastmodified: bookmark.lastmodified,
+            })
+            .collect()
+    }
+}
+
+/// The contents of the state file
+#[derive(Serialize, Deserialize, Debug)]
+struct State {
+    bookmarks: Vec<SBookmark>,
+    #[serde(default)]
+    revision_a: Option<String>,
+    #[serde(default)]
+    revision_b: Option<String>,
+    /// The ids of the synced Nextcloud folders, so an unchanged run need not look them up
+    #[serde(default)]
+    root_a: Option<i32>,
+    #[serde(default)]
+    root_b: Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum StateFile {
+    State(State),
+    // State files written before revisions were tracked only contain the bookmarks
+    Bookmarks(Vec<SBookmark>),
 }
 
 #[derive(Debug, Default)]
@@ -179,6 +586,22 @@ struct Changes {
     new: HashMap<String, Bookmark>,
     changed: HashMap<String, Bookmark>,
     deleted: HashMap<String, usize>,
+    /// Folders which are renamed as a whole, from the old to the new path
+    renamed: Vec<(Path, Path)>,
+    /// Whether the bookmarks have to be put in the order of the new state
+    reordered: bool,
+    /// Whether changes were left out, because the storage may not make them
+    dropped: bool,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.new.is_empty()
+            && self.changed.is_empty()
+            && self.deleted.is_empty()
+            && self.renamed.is_empty()
+            && !self.reordered
+    }
 }
 
 #[derive(Debug)]
@@ -195,28 +618,171 @@ struct Pair {
     a: Storage,
     b: Storage,
     conflict_resolution: ConflictResolution,
+    push_order: bool,
     previous_state: Option<Snapshot>,
+    lock_mode: LockMode,
 }
 
 impl Pair {
     /// The changes to be applied to a (first) and b (snd) to obtain the new snapshot
-    fn changes(&self) -> Result<Update, Error> {
-        let a = self.a.list()?;
-        let b = self.b.list()?;
+    ///
+    /// A side which is unchanged since the snapshot is not listed, unless it has to receive
+    /// changes from the other side.
+    fn changes(&mut self, unchanged_a: bool, unchanged_b: bool) -> Result<Update, Error> {
+        let snapshot = match &self.previous_state {
+            Some(snapshot) => snapshot,
+            None => {
+                let a = self.a.list()?;
+                let b = self.b.list()?;
+                let mut update = self.changes_initial(a.clone(), b.clone())?;
+                self.drop_unwritable(&mut update, &a, &b);
+                return Ok(update);
+            }
+        };
+
+        let mut a = if unchanged_a {
+            snapshot.as_bookmarks()
+        } else {
+            Pair::list(&mut self.a, snapshot)?
+        };
+        let mut b = if unchanged_b {
+            snapshot.as_bookmarks()
+        } else {
+            Pair::list(&mut self.b, snapshot)?
+        };
+
+        let mut update = self.changes_with_snapshot(a.clone(), b.clone(), snapshot)?;
+
+        // The snapshot has no ids, so a side receiving changes needs to be listed after all
+        let list_a = unchanged_a && !update.a.is_empty();
+        let list_b = unchanged_b && !update.b.is_empty();
+        if !list_a && !list_b {
+            self.drop_unwritable(&mut update, &a, &b);
+            return Ok(update);
+        }
+
+        if list_a {
+            a = Pair::list(&mut self.a, snapshot)?;
+        }
+        if list_b {
+            b = Pair::list(&mut self.b, snapshot)?;
+        }
+        let mut update = self.changes_with_snapshot(a.clone(), b.clone(), snapshot)?;
+        self.drop_unwritable(&mut update, &a, &b);
+        Ok(update)
+    }
+
+    fn drop_unwritable(&self, update: &mut Update, a: &[Bookmark], b: &[Bookmark]) {
+        Pair::keep_unwritable(&mut update.a, &mut update.new_state, &self.a, a);
+        Pair::keep_unwritable(&mut update.b, &mut update.new_state, &self.b, b);
+    }
+
+    /// Leaves out the changes which the storage may not make, like edits in read-only shares
+    ///
+    /// Their bookmarks stay as they are in the storage in the new state, so the next run finds
+    /// the same changes again.
+    fn keep_unwritable(
+        changes: &mut Changes,
+        new_state: &mut Vec<SBookmark>,
+        storage: &Storage,
+        current: &[Bookmark],
+    ) {
+        let nc_storage = match storage {
+            Storage::Nextcloud(nc_storage) => nc_storage,
+            Storage::File(_) => return,
+        };
+
+        let mut dropped: HashSet<Path> = HashSet::new();
+        let mut writable = |name: &Path| {
+            nc_storage.writable(name) || {
+                dropped.insert(name.clone());
+                false
+            }
+        };
+        changes.new.retain(|name, _| writable(name));
+        changes.changed.retain(|name, _| writable(name));
+        changes.deleted.retain(|name, _| writable(name));
+        changes.renamed.retain(|(from, to)| {
+            let from_dir = from.clone() + "/";
+            let to_dir = to.clone() + "/";
+            if nc_storage.writable(&from_dir) && nc_storage.writable(&to_dir) {
+                return true;
+            }
+            let names = new_state
+                .iter()
+                .map(|bookmark| &bookmark.name)
+                .chain(current.iter().map(|bookmark| &bookmark.name));
+            for name in names {
+                if name.starts_with(&from_dir) || name.starts_with(&to_dir) {
+                    dropped.insert(name.clone());
+                }
+            }
+            false
+        });
+
+        if dropped.is_empty() {
+            return;
+        }
+        changes.dropped = true;
+
+        let mut current: HashMap<&Path, &Bookmark> = current
+            .iter()
+            .filter(|bookmark| dropped.contains(&bookmark.name))
+            .map(|bookmark| (&bookmark.name, bookmark))
+            .collect();
+        let kept = |bookmark: &Bookmark| SBookmark {
+            name: bookmark.name.clone(),
+            url: bookmark.url.clone(),
+            lastmodified: bookmark.lastmodified,
+        };
+
+        let mut kept_state = Vec::new();
+        for bookmark in new_state.drain(..) {
+            if !dropped.contains(&bookmark.name) {
+                kept_state.push(bookmark);
+            } else if let Some(current_bookmark) = current.remove(&bookmark.name) {
+                kept_state.push(kept(current_bookmark));
+            }
+        }
+        kept_state.extend(current.into_values().map(kept));
+        *new_state = kept_state;
+    }
 
-        match &self.previous_state {
-            Some(snapshot) => self.changes_with_snapshot(a, b, &snapshot),
-            None => self.changes_initial(a, b),
+    /// Lists the storage, where the lines of a file which did not change since the snapshot keep
+    /// their time from the snapshot, instead of the modification time of the whole file
+    fn list(storage: &mut Storage, snapshot: &Snapshot) -> Result<Vec<Bookmark>, Error> {
+        let mut bookmarks = storage.list()?;
+
+        if let Storage::File(_) = storage {
+            let times: HashMap<(&str, &str), UnixTime> = snapshot
+                .bookmarks
+                .iter()
+                .map(|bookmark| {
+                    (
+                        (bookmark.name.as_str(), bookmark.url.as_str()),
+                        bookmark.lastmodified,
+                    )
+                })
+                .collect();
+            for bookmark in bookmarks.iter_mut() {
+                if let Some(time) = times.get(&(bookmark.name.as_str(), bookmark.url.as_str())) {
+                    bookmark.lastmodified = *time;
+                }
+            }
         }
+
+        Ok(bookmarks)
     }
 
     fn changes_initial(&self, a: Vec<Bookmark>, b: Vec<Bookmark>) -> Result<Update, Error> {
+        let order_a = Pair::order(&a);
+        let order_b = Pair::order(&b);
         let a_is_master = ConflictResolution::AWins == self.conflict_resolution;
         let (mut master, mut slave) = if a_is_master { (a, b) } else { (b, a) };
 
         let mut new_state: HashMap<String, Bookmark> = master
             .drain(..)
-            .map(|bookmark| return (bookmark.name.clone(), bookmark))
+            .map(|bookmark| (bookmark.name.clone(), bookmark))
             .collect();
 
         let slave_keys: HashSet<String> =
@@ -283,7 +849,7 @@ impl Pair {
         } else {
             (changes_slave, changes_master)
         };
-        Ok(Update {
+        let mut update = Update {
             a: changes_a,
             b: changes_b,
             new_state: new_state
@@ -294,7 +860,9 @@ impl Pair {
                     lastmodified: v.lastmodified,
                 })
                 .collect(),
-        })
+        };
+        self.arrange(&mut update, &order_a, &order_b);
+        Ok(update)
     }
 
     fn changes_with_snapshot(
@@ -303,6 +871,8 @@ impl Pair {
         b: Vec<Bookmark>,
         snapshot: &Snapshot,
     ) -> Result<Update, Error> {
+        let order_a = Pair::order(&a);
+        let order_b = Pair::order(&b);
         let a_ids: HashMap<String, usize> = a
             .iter()
             .map(|bookmark| (bookmark.name.clone(), bookmark.id))
@@ -321,9 +891,10 @@ impl Pair {
         let mut changes_b = Pair::compare_to_snapshot(b, snapshot);
 
         // Remove conflicts
-        self.handle_duplicates(&mut changes_a.new, &mut changes_b.new)?;
-        self.handle_duplicates(&mut changes_a.changed, &mut changes_b.changed)?;
-        self.handle_duplicates(&mut changes_a.deleted, &mut changes_b.deleted)?;
+        let same_url = |x: &Bookmark, y: &Bookmark| x.url == y.url;
+        self.handle_duplicates(&mut changes_a.new, &mut changes_b.new, same_url)?;
+        self.handle_duplicates(&mut changes_a.changed, &mut changes_b.changed, same_url)?;
+        self.handle_duplicates(&mut changes_a.deleted, &mut changes_b.deleted, |_, _| true)?;
 
         // changes_a need to be applied on b and vice versa
         self.change_ids(&mut changes_a, &b_ids);
@@ -349,12 +920,225 @@ impl Pair {
             })
             .collect();
 
+        // Folders renamed on one side are renamed on the other side, instead of recreated
+        Pair::detect_renames(&mut changes_a, &changes_b, snapshot);
+        Pair::detect_renames(&mut changes_b, &changes_a, snapshot);
+
         // Then these changes can applied on the other pair
-        Ok(Update {
+        let mut update = Update {
             a: changes_b,
             b: changes_a,
             new_state,
-        })
+        };
+        self.arrange(&mut update, &order_a, &order_b);
+        Ok(update)
+    }
+
+    fn order(bookmarks: &[Bookmark]) -> Vec<Path> {
+        bookmarks
+            .iter()
+            .map(|bookmark| bookmark.name.clone())
+            .collect()
+    }
+
+    /// Orders the new state like the leading side, and marks the sides which have to follow it
+    ///
+    /// Bookmarks which the leading side lacks go after the bookmark preceding them on the other
+    /// side.
+    fn arrange(&self, update: &mut Update, order_a: &[Path], order_b: &[Path]) {
+        let (leading, other) = if self.b_leads() {
+            (order_b, order_a)
+        } else {
+            (order_a, order_b)
+        };
+
+        let mut order: Vec<&Path> = leading.iter().collect();
+        let mut placed: HashSet<&Path> = leading.iter().collect();
+        for (i, name) in other.iter().enumerate() {
+            if !placed.insert(name) {
+                continue;
+            }
+            let at = match i {
+                0 => 0,
+                _ => order
+                    .iter()
+                    .position(|placed_name| *placed_name == &other[i - 1])
+                    .map_or(order.len(), |position| position + 1),
+            };
+            order.insert(at, name);
+        }
+
+        let positions: HashMap<&Path, usize> = order
+            .iter()
+            .enumerate()
+            .map(|(position, name)| (*name, position))
+            .collect();
+        update.new_state.sort_by(|x, y| {
+            let position = |bookmark: &SBookmark| positions.get(&bookmark.name).copied();
+            (position(x).unwrap_or(usize::MAX), &x.name)
+                .cmp(&(position(y).unwrap_or(usize::MAX), &y.name))
+        });
+
+        let new_order: Vec<&Path> = update
+            .new_state
+            .iter()
+            .map(|bookmark| &bookmark.name)
+            .collect();
+        // A sorted or grouped file is rewritten when its lines are not arranged yet
+        let reordered = |storage: &Storage, order: &[Path]| {
+            (self.follows_order(storage) && Pair::reordered(order, &new_order))
+                || matches!(storage, Storage::File(fs_storage)
+                    if !fs_storage.arranged(&update.new_state))
+        };
+        let (reordered_a, reordered_b) = (reordered(&self.a, order_a), reordered(&self.b, order_b));
+        update.a.reordered = reordered_a;
+        update.b.reordered = reordered_b;
+    }
+
+    /// Whether the new state follows the order of b instead of a
+    ///
+    /// Nextcloud leads if it keeps an order, unless the order of the file is pushed to Nextcloud.
+    /// Otherwise the file leads, as Nextcloud lists its bookmarks in no particular order.
+    fn b_leads(&self) -> bool {
+        let rank = |storage: &Storage| match storage {
+            Storage::Nextcloud(nc_storage)
+                if !self.push_order && nc_storage.capabilities.childorder == Some(true) =>
+            {
+                2
+            }
+            Storage::File(_) => 1,
+            Storage::Nextcloud(_) => 0,
+        };
+        rank(&self.b) > rank(&self.a)
+    }
+
+    fn follows_order(&self, storage: &Storage) -> bool {
+        match storage {
+            // A sorted file has an order of its own
+            Storage::File(fs_storage) => fs_storage.sort.is_none(),
+            Storage::Nextcloud(_) => self.push_order,
+        }
+    }
+
+    /// Whether the bookmarks which are in both orders, are in another order
+    fn reordered(current: &[Path], new_order: &[&Path]) -> bool {
+        let current_names: HashSet<&Path> = current.iter().collect();
+        let new_names: HashSet<&Path> = new_order.iter().copied().collect();
+
+        current
+            .iter()
+            .filter(|name| new_names.contains(name))
+            .ne(new_order
+                .iter()
+                .copied()
+                .filter(|name| current_names.contains(name)))
+    }
+
+    /// Replaces the deletes and creates of all bookmarks in a folder by a rename of that folder
+    ///
+    /// A rename is only detected when every bookmark of the old folder in the snapshot reappears
+    /// with the same url in the new folder, the new folder did not exist yet and the other side
+    /// did not touch either folder.
+    fn detect_renames(changes: &mut Changes, other: &Changes, snapshot: &Snapshot) {
+        let snapshot_urls: HashMap<&str, &str> = snapshot
+            .bookmarks
+            .iter()
+            .map(|bookmark| (bookmark.name.as_str(), bookmark.url.as_str()))
+            .collect();
+
+        let mut new_by_url: HashMap<&str, Vec<&str>> = HashMap::new();
+        for bookmark in changes.new.values() {
+            new_by_url
+                .entry(bookmark.url.as_str())
+                .or_default()
+                .push(bookmark.name.as_str());
+        }
+
+        let mut candidates: Vec<(Path, Path)> = Vec::new();
+        for deleted in changes.deleted.keys() {
+            let url = match snapshot_urls.get(deleted.as_str()) {
+                Some(url) => url,
+                None => continue,
+            };
+            for new in new_by_url.get(url).into_iter().flatten() {
+                if let Some(candidate) = Pair::renamed_folder(deleted, new) {
+                    if !candidates.contains(&candidate) {
+                        candidates.push(candidate);
+                    }
+                }
+            }
+        }
+
+        // Outer folders first, their renames cover the folders inside
+        candidates.sort_by_key(|(from, _)| from.len());
+
+        for (from, to) in candidates {
+            let from_dir = from.clone() + "/";
+            let to_dir = to.clone() + "/";
+
+            let moved: Vec<(&String, String)> = snapshot
+                .bookmarks
+                .iter()
+                .filter(|bookmark| bookmark.name.starts_with(&from_dir))
+                .map(|bookmark| {
+                    let new_name = to_dir.clone() + &bookmark.name[from_dir.len()..];
+                    (&bookmark.name, new_name)
+                })
+                .collect();
+
+            let complete = moved.iter().all(|(old_name, new_name)| {
+                let old_url = snapshot_urls.get(old_name.as_str()).copied();
+                let new_url = changes
+                    .new
+                    .get(new_name)
+                    .map(|bookmark| bookmark.url.as_str());
+                changes.deleted.contains_key(*old_name) && new_url.is_some() && new_url == old_url
+            });
+            let target_exists = snapshot
+                .bookmarks
+                .iter()
+                .any(|bookmark| bookmark.name == to || bookmark.name.starts_with(&to_dir));
+            let touched = |name: &String| name.starts_with(&from_dir) || name.starts_with(&to_dir);
+            let other_touched = other.new.keys().any(touched)
+                || other.changed.keys().any(touched)
+                || other.deleted.keys().any(touched);
+
+            if moved.is_empty() || !complete || target_exists || other_touched {
+                continue;
+            }
+
+            for (old_name, new_name) in moved {
+                changes.deleted.remove(old_name);
+                changes.new.remove(&new_name);
+            }
+            changes.renamed.push((from, to));
+        }
+    }
+
+    /// The folders which differ between the old and new path of a bookmark, if both have one
+    fn renamed_folder(old: &str, new: &str) -> Option<(Path, Path)> {
+        let old_parts: Vec<&str> = old.split('/').collect();
+        let new_parts: Vec<&str> = new.split('/').collect();
+
+        let common = old_parts
+            .iter()
+            .rev()
+            .zip(new_parts.iter().rev())
+            .take_while(|(old_part, new_part)| old_part == new_part)
+            .count();
+
+        if common == 0 || common == old_parts.len() || common == new_parts.len() {
+            return None;
+        }
+
+        let from = old_parts[..old_parts.len() - common].join("/");
+        let to = new_parts[..new_parts.len() - common].join("/");
+
+        // A folder can not be moved into itself, nor renamed to one of its parents
+        if to.starts_with(&(from.clone() + "/")) || from.starts_with(&(to.clone() + "/")) {
+            return None;
+        }
+        Some((from, to))
     }
 
     fn change_ids(&self, changes: &mut Changes, new_ids: &HashMap<String, usize>) {
@@ -374,10 +1158,22 @@ impl Pair {
         &self,
         a: &mut HashMap<String, T>,
         b: &mut HashMap<String, T>,
+        same: impl Fn(&T, &T) -> bool,
     ) -> Result<(), Error> {
+        // A change made on both sides is no conflict, e.g. after a sync which stopped halfway
+        let made_twice: Vec<String> = a
+            .iter()
+            .filter(|(key, x)| b.get(*key).is_some_and(|y| same(x, y)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in made_twice {
+            a.remove(&key);
+            b.remove(&key);
+        }
+
         match &self.conflict_resolution {
             ConflictResolution::AWins => {
-                for (key, _) in a {
+                for key in a.keys() {
                     if b.contains_key(key) {
                         b.remove(key);
                     }
@@ -385,7 +1181,7 @@ impl Pair {
                 Ok(())
             }
             ConflictResolution::BWins => {
-                for (key, _) in b {
+                for key in b.keys() {
                     if a.contains_key(key) {
                         a.remove(key);
                     }
@@ -393,7 +1189,7 @@ impl Pair {
                 Ok(())
             }
             ConflictResolution::Error => {
-                for (key, _) in b {
+                for key in b.keys() {
                     if a.contains_key(key) {
                         Err(Error::Conflict(key.clone()))?;
                     };
@@ -411,7 +1207,7 @@ impl Pair {
         let mut snapshot_hash: HashMap<String, &SBookmark> = snapshot
             .bookmarks
             .iter()
-            .map(|bookmark| return (bookmark.name.clone(), bookmark))
+            .map(|bookmark| (bookmark.name.clone(), bookmark))
             .collect();
 
         let mut new: HashMap<String, Bookmark> = HashMap::new();
@@ -440,36 +1236,123 @@ impl Pair {
             deleted,
             new,
             changed,
+            renamed: Vec::new(),
+            reordered: false,
+            dropped: false,
         }
     }
 
     pub fn new(
         state_file: String,
+        lock_mode: LockMode,
         cfg: &PairConfig,
         cfg_a: &StorageConfig,
         cfg_b: &StorageConfig,
     ) -> Result<Pair, Error> {
-        let a = Storage::from_config(cfg_a)?;
-        let b = Storage::from_config(cfg_b)?;
-
-        let previous_state = Pair::read_state(&state_file)?;
+        let mut a = Storage::from_config(cfg_a)?;
+        let mut b = Storage::from_config(cfg_b)?;
+        for storage in [&mut a, &mut b] {
+            match storage {
+                Storage::Nextcloud(nc_storage) => nc_storage.push_order = cfg.push_order,
+                Storage::File(fs_storage) => fs_storage.lock_mode = lock_mode,
+            }
+        }
 
         Ok(Pair {
             state_file,
             a,
             b,
             conflict_resolution: cfg.conflict_resolution.clone(),
-            previous_state,
+            push_order: cfg.push_order,
+            previous_state: None,
+            lock_mode,
         })
     }
 
+    /// Takes the lock of the pair, which is held until the returned file is dropped
+    fn lock(&self) -> Result<fs::File, Error> {
+        let path = self.state_file.clone() + ".lock";
+        if let Some(parent) = std::path::Path::new(&path).parent() {
+            fs::create_dir_all(parent).map_err(Error::IOError)?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(Error::IOError)?;
+        FileStorage::lock(&file, &path, self.lock_mode)?;
+        Ok(file)
+    }
+
     pub fn run(&mut self) -> Result<(), Error> {
-        let update = self.changes()?;
+        // Another run may have changed the state while this one waited for the lock
+        let _lock = self.lock()?;
+        self.previous_state = Pair::read_state(&self.state_file)?;
+        if let Some(snapshot) = &self.previous_state {
+            self.a.remember_root(snapshot.root_a);
+            self.b.remember_root(snapshot.root_b);
+        }
+
+        let mut revision_a = self.a.revision()?;
+        let mut revision_b = self.b.revision()?;
+
+        let (unchanged_a, unchanged_b) = match &self.previous_state {
+            Some(snapshot) => (
+                revision_a.is_some() && revision_a == snapshot.revision_a,
+                revision_b.is_some() && revision_b == snapshot.revision_b,
+            ),
+            None => (false, false),
+        };
+
+        if unchanged_a && unchanged_b {
+            return Ok(());
+        }
+
+        let update = self.changes(unchanged_a, unchanged_b)?;
+        let update_a_dropped = update.a.dropped;
+        let update_b_dropped = update.b.dropped;
+
+        // Changes made to a side during the sync would be overwritten, so nothing is applied
+        self.a.verify()?;
+        self.b.verify()?;
+
+        // Files go first, so a file edited meanwhile stops the sync before Nextcloud is changed
+        let Update {
+            a: changes_a,
+            b: changes_b,
+            new_state,
+        } = update;
+        let mut sides = [
+            (&mut self.a, changes_a, &mut revision_a),
+            (&mut self.b, changes_b, &mut revision_b),
+        ];
+        if let [(Storage::Nextcloud(_), ..), (Storage::File(_), ..)] = &sides {
+            sides.reverse();
+        }
+        for (storage, changes, revision) in sides {
+            if !changes.is_empty() {
+                storage.apply(changes, &new_state)?;
+                *revision = storage.revision()?;
+            }
+        }
 
-        self.a.apply(update.a, &update.new_state)?;
-        self.b.apply(update.b, &update.new_state)?;
+        // The side with the left out changes is listed again, so they are found again
+        if update_a_dropped {
+            revision_b = None;
+        }
+        if update_b_dropped {
+            revision_a = None;
+        }
 
-        self.write_state(update.new_state)
+        self.write_state(State {
+            bookmarks: new_state,
+            revision_a,
+            revision_b,
+            root_a: self.a.root(),
+            root_b: self.b.root(),
+        })
     }
 
     fn read_state(state_file: &str) -> Result<Option<Snapshot>, Error> {
@@ -477,12 +1360,27 @@ impl Pair {
 
         match result {
             Ok(cnt) => {
-                let bookmarks: Vec<SBookmark> =
-                    serde_json::from_str(&cnt).map_err(Error::SerdeError)?;
+                let state = match serde_json::from_str(&cnt).map_err(Error::SerdeError)? {
+                    StateFile::State(state) => state,
+                    StateFile::Bookmarks(bookmarks) => State {
+                        bookmarks,
+                        revision_a: None,
+                        revision_b: None,
+                        root_a: None,
+                        root_b: None,
+                    },
+                };
 
                 let at = FileStorage::file_modified(state_file)?;
 
-                Ok(Some(Snapshot { bookmarks, at }))
+                Ok(Some(Snapshot {
+                    at,
+                    bookmarks: state.bookmarks,
+                    revision_a: state.revision_a,
+                    revision_b: state.revision_b,
+                    root_a: state.root_a,
+                    root_b: state.root_b,
+                }))
             }
             Err(error) => match error.kind() {
                 std::io::ErrorKind::NotFound => Ok(None),
@@ -491,54 +1389,101 @@ impl Pair {
         }
     }
 
-    fn write_state(&self, bookmarks: Vec<SBookmark>) -> Result<(), Error> {
+    fn write_state(&self, state: State) -> Result<(), Error> {
         let path = std::path::Path::new(&self.state_file);
         let parent = path.parent().unwrap();
 
         if !parent.exists() {
-            let _ = fs::create_dir_all(parent).map_err(Error::IOError)?;
+            fs::create_dir_all(parent).map_err(Error::IOError)?;
         }
 
-        let bytes = serde_json::to_string(&bookmarks)
+        let bytes = serde_json::to_string(&state)
             .map_err(Error::SerdeError)?
             .into_bytes();
 
-        let mut f = fs::File::create(path).map_err(Error::IOError)?;
-        f.write_all(&bytes).map_err(Error::IOError)?;
-
-        Ok(())
+        FileStorage::write_atomic(path, &bytes)
     }
 }
 
 // STORAGE
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Storage {
     File(FileStorage),
     Nextcloud(NextcloudStorage),
 }
 
 impl Storage {
-    pub fn apply(&mut self, changes: Changes, new_state: &Vec<SBookmark>) -> Result<(), Error> {
+    pub fn apply(&mut self, changes: Changes, new_state: &[SBookmark]) -> Result<(), Error> {
         match self {
             Storage::File(fs_storage) => fs_storage.apply(changes, new_state),
             Storage::Nextcloud(nc_storage) => nc_storage.apply(changes, new_state),
         }
     }
 
-    pub fn list(&self) -> Result<Vec<Bookmark>, Error> {
+    pub fn list(&mut self) -> Result<Vec<Bookmark>, Error> {
         match self {
             Storage::File(fs_storage) => fs_storage.list(),
             Storage::Nextcloud(nc_storage) => nc_storage.list(),
         }
     }
 
-    pub fn from_config(cfg: &StorageConfig) -> Result<Storage, Error> {
-        match cfg._type {
-            StorageType::Nextcloud => Storage::from_config_nc(cfg),
-            StorageType::File => {
-                if let Some(path) = &cfg.path {
-                    Ok(Storage::File(FileStorage {
-                        path: path.to_owned(),
+    /// Fails if the storage changed since it was listed
+    pub fn verify(&self) -> Result<(), Error> {
+        match self {
+            Storage::File(fs_storage) => fs_storage.verify().map(|_| ()),
+            Storage::Nextcloud(_) => Ok(()),
+        }
+    }
+
+    /// A value which changes whenever the bookmarks in the storage change, if the storage has one
+    pub fn revision(&mut self) -> Result<Option<String>, Error> {
+        match self {
+            Storage::File(fs_storage) => fs_storage.revision(),
+            Storage::Nextcloud(nc_storage) => nc_storage.revision(),
+        }
+    }
+
+    /// The id of the synced Nextcloud folder, once it was looked up
+    pub fn root(&self) -> Option<i32> {
+        match self {
+            Storage::File(_) => None,
+            Storage::Nextcloud(nc_storage) => nc_storage.root,
+        }
+    }
+
+    /// Uses the id of the synced Nextcloud folder from a previous run
+    pub fn remember_root(&mut self, root: Option<i32>) {
+        if let Storage::Nextcloud(nc_storage) = self {
+            if nc_storage.root.is_none() {
+                nc_storage.root = root;
+            }
+        }
+    }
+
+    pub fn from_config(cfg: &StorageConfig) -> Result<Storage, Error> {
+        match cfg._type {
+            StorageType::Nextcloud => Storage::from_config_nc(cfg),
+            StorageType::File => {
+                if let Some(path) = &cfg.path {
+                    let format = match &cfg.format {
+                        Some(format) => format.line_format()?,
+                        None => FormatConfig::Preset(Preset::Quickmarks).line_format()?,
+                    };
+                    Ok(Storage::File(FileStorage {
+                        path: path.to_owned(),
+                        format,
+                        malformed_lines: cfg.malformed_lines.clone(),
+                        listed: None,
+                        listed_content: String::new(),
+                        lock_mode: LockMode::default(),
+                        lock: None,
+                        sort: cfg.sort.clone(),
+                        group_folders: cfg.group_folders,
+                        read_filter: cfg.read_filter.clone(),
+                        write_filter: cfg.write_filter.clone(),
+                        backup_path: cfg.backup_path.clone(),
+                        backup_count: cfg.backup_count.unwrap_or(10),
                     }))
                 } else {
                     Err(Error::MissingConfig("path"))
@@ -549,83 +1494,839 @@ impl Storage {
 
     fn from_config_nc(cfg: &StorageConfig) -> Result<Storage, Error> {
         let url = cfg.url.as_ref().ok_or(Error::MissingConfig("url"))?;
-        let username_cmd = cfg
-            .username
-            .as_ref()
-            .ok_or(Error::MissingConfig("username"))?;
-        let passwd_cmd = cfg
-            .password
-            .as_ref()
-            .ok_or(Error::MissingConfig("password"))?;
 
-        let username = username_cmd.value()?;
-        let password = passwd_cmd.value()?;
+        let auth = match cfg.auth {
+            AuthMethod::Basic => {
+                let username_cmd = cfg
+                    .username
+                    .as_ref()
+                    .ok_or(Error::MissingConfig("username"))?;
+                let passwd_cmd = cfg
+                    .password
+                    .as_ref()
+                    .ok_or(Error::MissingConfig("password"))?;
+
+                Auth::Basic {
+                    username: username_cmd.value()?,
+                    password: passwd_cmd.value()?,
+                }
+            }
+            AuthMethod::Bearer => {
+                let token_cmd = cfg.token.as_ref().ok_or(Error::MissingConfig("token"))?;
+
+                Auth::Bearer(token_cmd.value()?)
+            }
+            AuthMethod::Header => {
+                let name = cfg.header.as_ref().ok_or(Error::MissingConfig("header"))?;
+                let token_cmd = cfg.token.as_ref().ok_or(Error::MissingConfig("token"))?;
+
+                Auth::Header {
+                    name: name.to_owned(),
+                    value: token_cmd.value()?,
+                }
+            }
+        };
 
-        NextcloudStorage::new(url.to_owned(), username.to_owned(), password.to_owned())
-            .map(Storage::Nextcloud)
+        NextcloudStorage::new(cfg, url.to_owned(), auth).map(Storage::Nextcloud)
     }
 }
 
 // FILESYSTEM
+/// How the fields of a bookmark are laid out on a line of a file storage
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LineFormat {
+    /// The fields of a line in order. Besides `name` and `url`, fields are kept as they are.
+    /// Fields ending in `?` may be left out at the end of a line.
+    fields: Vec<String>,
+    /// The text between the fields, or any whitespace if it is left out
+    separator: Option<String>,
+}
+
+impl LineFormat {
+    /// The byte ranges of the name and url in a line
+    ///
+    /// A line with more fields than the format has, has separators in its name.
+    fn spans(
+        &self,
+        line: &str,
+    ) -> Result<(std::ops::Range<usize>, std::ops::Range<usize>), String> {
+        let parts: Vec<(usize, usize)> = match &self.separator {
+            None => {
+                let mut parts = Vec::new();
+                let mut start = None;
+                for (i, c) in line.char_indices() {
+                    match (c.is_whitespace(), start) {
+                        (true, Some(begin)) => {
+                            parts.push((begin, i));
+                            start = None;
+                        }
+                        (false, None) => start = Some(i),
+                        _ => {}
+                    }
+                }
+                if let Some(begin) = start {
+                    parts.push((begin, line.len()));
+                }
+                parts
+            }
+            Some(separator) => {
+                let mut parts = Vec::new();
+                let mut start = 0;
+                for (i, _) in line.match_indices(separator.as_str()) {
+                    parts.push((start, i));
+                    start = i + separator.len();
+                }
+                parts.push((start, line.len()));
+                parts
+            }
+        };
+
+        if parts.len() < self.required() {
+            return Err(format!(
+                "expected the fields {}, separated by {}",
+                self.fields.join(", "),
+                self.separator
+                    .as_ref()
+                    .map_or("whitespace".to_owned(), |separator| format!(
+                        "{:?}",
+                        separator
+                    ))
+            ));
+        }
+
+        let name_index = self.index("name");
+        let extra = parts.len().saturating_sub(self.fields.len());
+        let span = |index: usize| match index {
+            i if i < name_index => parts[i].0..parts[i].1,
+            i if i == name_index => parts[i].0..parts[i + extra].1,
+            i => parts[i + extra].0..parts[i + extra].1,
+        };
+
+        Ok((span(name_index), span(self.index("url"))))
+    }
+
+    /// How many fields a line has at least, up to the last field which is not optional
+    fn required(&self) -> usize {
+        self.fields
+            .iter()
+            .rposition(|field| !field.ends_with('?'))
+            .map_or(0, |position| position + 1)
+    }
+
+    fn index(&self, field: &str) -> usize {
+        self.fields.iter().position(|f| f == field).unwrap()
+    }
+
+    /// A new line with the name and url, where the other fields are empty
+    fn write(&self, name: &str, url: &str) -> String {
+        let fields: Vec<&str> = self.fields[..self.required()]
+            .iter()
+            .map(|field| match field.as_str() {
+                "name" => name,
+                "url" => url,
+                _ => "",
+            })
+            .collect();
+
+        fields.join(self.separator.as_deref().unwrap_or(" "))
+    }
+
+    /// The line with another name and url, which keeps the other fields
+    fn replace(&self, line: &str, name: &str, url: &str) -> String {
+        let (name_span, url_span) = match self.spans(line) {
+            Ok(spans) => spans,
+            Err(_) => return self.write(name, url),
+        };
+
+        let mut replacements = [(name_span, name), (url_span, url)];
+        replacements.sort_by_key(|(span, _)| std::cmp::Reverse(span.start));
+
+        let mut line = line.to_owned();
+        for (span, value) in replacements {
+            line.replace_range(span, value);
+        }
+        line
+    }
+}
+
+/// A named line format, or the fields and separator of a line
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum FormatConfig {
+    Preset(Preset),
+    Custom(LineFormat),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum Preset {
+    /// `name url`, the qutebrowser quickmarks
+    #[serde(rename = "quickmarks")]
+    Quickmarks,
+    /// `url title`, the qutebrowser bookmarks/urls
+    #[serde(rename = "urls")]
+    Urls,
+    /// `url<TAB>title<TAB>tags`, the vimb bookmarks
+    #[serde(rename = "vimb")]
+    Vimb,
+    /// `name<TAB>url`
+    #[serde(rename = "tsv")]
+    Tsv,
+}
+
+impl FormatConfig {
+    fn line_format(&self) -> Result<LineFormat, Error> {
+        let fields = |fields: &[&str]| fields.iter().map(|field| field.to_string()).collect();
+        let format = match self {
+            FormatConfig::Preset(Preset::Quickmarks) => LineFormat {
+                fields: fields(&["name", "url"]),
+                separator: None,
+            },
+            FormatConfig::Preset(Preset::Urls) => LineFormat {
+                fields: fields(&["url", "name"]),
+                separator: None,
+            },
+            FormatConfig::Preset(Preset::Vimb) => LineFormat {
+                fields: fields(&["url", "name", "tags?"]),
+                separator: Some("\t".to_owned()),
+            },
+            FormatConfig::Preset(Preset::Tsv) => LineFormat {
+                fields: fields(&["name", "url"]),
+                separator: Some("\t".to_owned()),
+            },
+            FormatConfig::Custom(format) => format.clone(),
+        };
+
+        // The name and url are always needed, so they cannot be optional
+        let has = |field: &str| format.fields.iter().any(|f| f == field);
+        if !has("name") || !has("url") || format.separator.as_deref() == Some("") {
+            return Err(Error::MissingConfig("format.fields"));
+        }
+
+        // New lines leave the other fields empty, which whitespace can not separate
+        let empty_required = format.fields[..format.required()]
+            .iter()
+            .any(|field| field != "name" && field != "url");
+        if format.separator.is_none() && empty_required {
+            return Err(Error::MissingConfig("format.separator"));
+        }
+        Ok(format)
+    }
+}
+
 #[derive(Debug)]
 struct FileStorage {
     path: String,
+    format: LineFormat,
+    malformed_lines: MalformedLines,
+    /// The version of the file when it was listed
+    listed: Option<FileVersion>,
+    /// The text of the file when it was listed, such that the read filter runs once per sync
+    listed_content: String,
+    lock_mode: LockMode,
+    /// The locked file, which stays locked from the listing until the storage is dropped
+    lock: Option<fs::File>,
+    sort: Option<SortOrder>,
+    group_folders: bool,
+    backup_path: Option<String>,
+    backup_count: usize,
+    read_filter: Option<Command>,
+    write_filter: Option<Command>,
+}
+
+/// The modification time, size and hash of a file, where a missing file has the default
+#[derive(Debug, Default, PartialEq, Eq)]
+struct FileVersion {
+    modified: u128,
+    len: u64,
+    hash: Vec<u8>,
 }
 
 impl FileStorage {
-    pub fn apply(&self, _changes: Changes, new_state: &Vec<SBookmark>) -> Result<(), Error> {
+    /// Applies the changes to the lines of the file, which keeps comments, blank lines and the
+    /// order of the other lines
+    pub fn apply(&mut self, changes: Changes, new_state: &[SBookmark]) -> Result<(), Error> {
         let path = std::path::Path::new(&self.path);
         let parent = path.parent().unwrap();
 
         if !parent.exists() {
-            let _ = fs::create_dir_all(parent).map_err(Error::IOError)?;
+            fs::create_dir_all(parent).map_err(Error::IOError)?;
+        }
+
+        if self.lock.is_none() {
+            self.lock = Some(self.lock_file()?);
+        }
+        // The text is the one of the listing, so the read filter only runs for a file which was
+        // not listed
+        let bytes = self.verify()?;
+        let content = match self.listed {
+            Some(_) => self.listed_content.clone(),
+            None => self.decode(bytes.clone())?,
+        };
+        if !bytes.is_empty() {
+            self.backup(&bytes)?;
+        }
+        let mut lines: Vec<String> = content.lines().map(str::to_owned).collect();
+
+        // Changed and renamed lines are edited in place
+        let mut unwritable = Ok(());
+        lines.retain_mut(|line| {
+            let (old_name, url) = match self.bookmark_line(line) {
+                Some((name, url)) => (name.to_owned(), url.to_owned()),
+                None => return true,
+            };
+            if changes.deleted.contains_key(&old_name) {
+                return false;
+            }
+
+            let name = changes
+                .renamed
+                .iter()
+                .find_map(|(from, to)| {
+                    old_name
+                        .strip_prefix(&(from.clone() + "/"))
+                        .map(|rest| to.clone() + "/" + rest)
+                })
+                .unwrap_or_else(|| old_name.clone());
+            let (name, url) = match changes.changed.get(&name) {
+                Some(bookmark) => (bookmark.name.clone(), bookmark.url.clone()),
+                None if name != old_name => (name, url),
+                None => return true,
+            };
+            match self.checked_line(self.format.replace(line, &name, &url), &name, &url) {
+                Ok(replaced) => *line = replaced,
+                Err(error) => unwritable = Err(error),
+            }
+            true
+        });
+        unwritable?;
+
+        // A new line goes after the line which precedes it in the new state, or before the
+        // first bookmark if there is none
+        for (i, bookmark) in new_state.iter().enumerate() {
+            if !changes.new.contains_key(&bookmark.name) {
+                continue;
+            }
+
+            let at = new_state[..i]
+                .iter()
+                .rev()
+                .find_map(|previous| self.line_of(&lines, &previous.name))
+                .map(|position| position + 1)
+                .or_else(|| {
+                    lines
+                        .iter()
+                        .position(|line| self.bookmark_line(line).is_some())
+                })
+                .unwrap_or(lines.len());
+            let line = self.format.write(&bookmark.name, &bookmark.url);
+            lines.insert(at, self.checked_line(line, &bookmark.name, &bookmark.url)?);
+        }
+
+        self.arrange_lines(&mut lines, changes.reordered, new_state);
+
+        let mut content = lines.join("\n");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+
+        FileStorage::write_atomic(path, &self.encode(&content)?)
+    }
+
+    /// Whether the listed file is sorted and grouped as it would be written
+    ///
+    /// A file which was not listed did not change since the last sync, which wrote it.
+    fn arranged(&self, new_state: &[SBookmark]) -> bool {
+        if self.listed.is_none() || (self.sort.is_none() && !self.group_folders) {
+            return true;
+        }
+        let current: Vec<String> = self.listed_content.lines().map(str::to_owned).collect();
+        let mut lines = current.clone();
+        self.arrange_lines(&mut lines, false, new_state);
+        lines == current
+    }
+
+    /// Puts the lines of the bookmarks in the order of the new state or the sort order, and
+    /// groups the top level folders
+    fn arrange_lines(&self, lines: &mut Vec<String>, reordered: bool, new_state: &[SBookmark]) {
+        // Reordered or sorted bookmarks swap lines, while comments and blank lines stay where
+        // they are
+        if reordered || self.sort.is_some() {
+            let state: HashMap<&str, (usize, UnixTime)> = new_state
+                .iter()
+                .enumerate()
+                .map(|(position, bookmark)| {
+                    (bookmark.name.as_str(), (position, bookmark.lastmodified))
+                })
+                .collect();
+            let slots: Vec<usize> = (0..lines.len())
+                .filter(|i| self.bookmark_line(&lines[*i]).is_some())
+                .collect();
+
+            let mut bookmark_lines: Vec<String> = slots.iter().map(|i| lines[*i].clone()).collect();
+            bookmark_lines.sort_by(|x, y| {
+                let (x_name, x_url) = self.bookmark_line(x).unwrap();
+                let (y_name, y_url) = self.bookmark_line(y).unwrap();
+                let position = |name: &str| state.get(name).map_or(usize::MAX, |(p, _)| *p);
+                let time = |name: &str| state.get(name).map_or(UnixTime::MAX, |(_, t)| *t);
+                match self.sort {
+                    None => position(x_name).cmp(&position(y_name)),
+                    Some(SortOrder::Path) => x_name.cmp(y_name),
+                    Some(SortOrder::Url) => (x_url, x_name).cmp(&(y_url, y_name)),
+                    Some(SortOrder::Folder) => FileStorage::folder_and_title(x_name)
+                        .cmp(&FileStorage::folder_and_title(y_name)),
+                    Some(SortOrder::LastModified) => {
+                        (time(x_name), x_name).cmp(&(time(y_name), y_name))
+                    }
+                }
+            });
+            for (slot, line) in slots.into_iter().zip(bookmark_lines) {
+                lines[slot] = line;
+            }
+        }
+
+        // Top level folders are separated by a single blank line, and other blank lines go
+        if self.group_folders {
+            let mut grouped = Vec::new();
+            let mut previous: Option<String> = None;
+            for line in lines.drain(..) {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if let Some((name, _)) = self.bookmark_line(&line) {
+                    let folder = name.split_once('/').map_or("", |(folder, _)| folder);
+                    if previous.as_ref().is_some_and(|previous| previous != folder) {
+                        grouped.push(String::new());
+                    }
+                    previous = Some(folder.to_owned());
+                }
+                grouped.push(line);
+            }
+            *lines = grouped;
+        }
+    }
+
+    fn folder_and_title(name: &str) -> (&str, &str) {
+        name.rsplit_once('/').unwrap_or(("", name))
+    }
+
+    /// Opens and locks the lock file next to the file
+    ///
+    /// The file itself is replaced on every write, which would leave a lock on it behind on the
+    /// old file, so the lock file is never replaced.
+    fn lock_file(&self) -> Result<fs::File, Error> {
+        let path = std::path::Path::new(&self.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(Error::IOError)?;
+        }
+        // A symlinked file is written at its target, so it is locked there as well
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let lock_path = path.to_string_lossy().into_owned() + ".lock";
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(Error::IOError)?;
+        FileStorage::lock(&file, &self.path, self.lock_mode)?;
+        Ok(file)
+    }
+
+    /// Takes an exclusive advisory lock on the file, which waits for or fails on other locks
+    pub fn lock(file: &fs::File, path: &str, mode: LockMode) -> Result<(), Error> {
+        match mode {
+            LockMode::Wait => file.lock_exclusive().map_err(Error::IOError),
+            LockMode::Fail => match file.try_lock_exclusive() {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(Error::Locked(path.to_owned())),
+                Err(error) => Err(Error::IOError(error)),
+            },
+        }
+    }
+
+    /// Copies the bytes of the file to a new backup, unless the newest backup has the same
+    /// bytes, and removes the oldest backups beyond the backup count
+    fn backup(&self, bytes: &[u8]) -> Result<(), Error> {
+        let backup_path = match &self.backup_path {
+            Some(backup_path) => std::path::Path::new(backup_path),
+            None => return Ok(()),
+        };
+        // The backups are only for the owner of the files
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(backup_path).map_err(Error::IOError)?;
+
+        let mut backups = self.backups()?;
+        let newest = backups
+            .last()
+            .and_then(|backup| fs::read(backup_path.join(backup)).ok());
+        if newest.as_deref() != Some(bytes) {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map_err(Error::TimeError)?
+                .as_millis();
+            let backup = self.backup_prefix() + &FileStorage::timestamp(now);
+            // The backup is as private as the file
+            let backup_file = backup_path.join(&backup);
+            FileStorage::write_atomic_like(&backup_file, bytes, std::path::Path::new(&self.path))?;
+            backups.push(backup);
         }
 
-        let lines: String = new_state
+        let excess = backups.len().saturating_sub(self.backup_count);
+        for backup in &backups[..excess] {
+            fs::remove_file(backup_path.join(backup)).map_err(Error::IOError)?;
+        }
+
+        Ok(())
+    }
+
+    /// The names of the backups of the file, the oldest first
+    pub fn backups(&self) -> Result<Vec<String>, Error> {
+        let backup_path = self
+            .backup_path
+            .as_ref()
+            .ok_or(Error::MissingConfig("backup_path"))?;
+        let prefix = self.backup_prefix();
+
+        let entries = match fs::read_dir(backup_path) {
+            Ok(entries) => entries,
+            Err(error) => match error.kind() {
+                std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                _ => return Err(Error::IOError(error)),
+            },
+        };
+
+        let mut backups = Vec::new();
+        for entry in entries {
+            let name = entry.map_err(Error::IOError)?.file_name();
+            let name = name.to_string_lossy();
+            let is_backup = name
+                .strip_prefix(&prefix)
+                .is_some_and(FileStorage::is_timestamp);
+            if is_backup {
+                backups.push(name.into_owned());
+            }
+        }
+
+        // The timestamps sort in the order of time
+        backups.sort();
+        Ok(backups)
+    }
+
+    /// Replaces the file by the backup, after backing up the file itself
+    pub fn restore(&self, backup: &str) -> Result<(), Error> {
+        let backup_path = self
+            .backup_path
+            .as_ref()
+            .ok_or(Error::MissingConfig("backup_path"))?;
+        let bytes =
+            fs::read(std::path::Path::new(backup_path).join(backup)).map_err(Error::IOError)?;
+
+        let _lock = self.lock_file()?;
+        if let Some(current) = self.read()? {
+            self.backup(&current)?;
+        }
+        FileStorage::write_atomic(std::path::Path::new(&self.path), &bytes)
+    }
+
+    /// The start of the backup names, the file name and a hash of the full path, such that files
+    /// with the same name share a backup directory
+    fn backup_prefix(&self) -> String {
+        let path = std::path::Path::new(&self.path);
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let full_path = fs::canonicalize(path)
+            .or_else(|_| std::path::absolute(path))
+            .unwrap_or_else(|_| path.to_path_buf());
+        let digest = ring::digest::digest(
+            &ring::digest::SHA256,
+            full_path.as_os_str().as_encoded_bytes(),
+        );
+        let hash: String = digest.as_ref()[..4]
             .iter()
-            .map(|bookmark| {
-                let line: String = bookmark.name.clone() + " " + &bookmark.url + "\n";
-                line
-            })
+            .map(|byte| format!("{:02x}", byte))
             .collect();
 
-        let bytes = lines.into_bytes();
+        format!("{}.{}.", file_name, hash)
+    }
+
+    /// Whether the text has the form of `timestamp`, like `20240131T235959.999Z`
+    fn is_timestamp(text: &str) -> bool {
+        let digits = |part: &str, len: usize| {
+            part.len() == len && part.bytes().all(|byte| byte.is_ascii_digit())
+        };
+        match text.split_once('T') {
+            Some((date, time)) => {
+                digits(date, 8)
+                    && time.strip_suffix('Z').is_some_and(|time| {
+                        time.split_once('.')
+                            .is_some_and(|(secs, millis)| digits(secs, 6) && digits(millis, 3))
+                    })
+            }
+            None => false,
+        }
+    }
+
+    /// Formats a Unix time in milliseconds as UTC, like `20240131T235959.999Z`
+    fn timestamp(millis: u128) -> String {
+        let secs = (millis / 1000) as i64;
+        let (days, time) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+        // The civil date of the days since 1970-01-01, from Howard Hinnant's date algorithms
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            time / 3600,
+            time % 3600 / 60,
+            time % 60,
+            millis % 1000
+        )
+    }
+
+    /// The name and url of a line, or `None` for comments and blank lines
+    fn parse_line<'a>(&self, line: &'a str) -> Result<Option<(&'a str, &'a str)>, String> {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (name, url) = self.format.spans(line)?;
+        let (name, url) = (line[name].trim(), line[url].trim());
 
-        let mut f = fs::File::create(path).map_err(Error::IOError)?;
-        f.write_all(&bytes).map_err(Error::IOError)?;
+        if name.is_empty() {
+            return Err("the name is empty".to_owned());
+        }
+        if reqwest::Url::parse(url).is_err() {
+            return Err(format!("`{}` is not a valid url", url));
+        }
+        Ok(Some((name, url)))
+    }
+
+    /// Returns the line if it reads back as the bookmark, which is lost from the file otherwise
+    fn checked_line(&self, line: String, name: &str, url: &str) -> Result<String, Error> {
+        let unwritable =
+            |reason: &str| Error::Unwritable(name.to_owned(), self.path.clone(), reason.to_owned());
+        match self.parse_line(&line) {
+            Ok(Some(parsed)) if parsed == (name.trim(), url.trim()) => Ok(line),
+            Ok(Some(_)) => Err(unwritable("the line reads back as another bookmark")),
+            Ok(None) => Err(unwritable("the line reads back as a comment")),
+            Err(message) => Err(unwritable(&message)),
+        }
+    }
+
+    /// The name and url of a line, or `None` if the line is no bookmark
+    fn bookmark_line<'a>(&self, line: &'a str) -> Option<(&'a str, &'a str)> {
+        self.parse_line(line).ok().flatten()
+    }
+
+    fn line_of(&self, lines: &[String], name: &str) -> Option<usize> {
+        lines.iter().position(|line| {
+            self.bookmark_line(line)
+                .is_some_and(|(line_name, _)| line_name == name)
+        })
+    }
+
+    /// Replaces the contents of the file, such that a crash leaves either the old or the new
+    /// contents behind
+    ///
+    /// The contents go to a temporary file next to it, which is renamed over the file once it is
+    /// on disk. A symlinked file is replaced at its target.
+    pub fn write_atomic(path: &std::path::Path, bytes: &[u8]) -> Result<(), Error> {
+        FileStorage::write_atomic_like(path, bytes, path)
+    }
+
+    /// Like `write_atomic`, where the file gets the permissions of the file `like`, from before
+    /// any contents are written
+    pub fn write_atomic_like(
+        path: &std::path::Path,
+        bytes: &[u8],
+        like: &std::path::Path,
+    ) -> Result<(), Error> {
+        let permissions = fs::metadata(like)
+            .map(|metadata| metadata.permissions())
+            .ok();
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let parent = path.parent().unwrap();
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let temp_path = parent.join(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+        let result = (|| -> std::io::Result<()> {
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            if let Some(permissions) = &permissions {
+                use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+                options.mode(permissions.mode());
+            }
+            let mut f = options.open(&temp_path)?;
+            if let Some(permissions) = permissions {
+                f.set_permissions(permissions)?;
+            }
+            f.write_all(bytes)?;
+            f.sync_all()?;
+            fs::rename(&temp_path, &path)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result.map_err(Error::IOError)?;
+
+        // The rename itself is only durable once the directory is synced
+        #[cfg(unix)]
+        fs::File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .map_err(Error::IOError)?;
 
         Ok(())
     }
 
-    pub fn list(&self) -> Result<Vec<Bookmark>, Error> {
-        let result = fs::read_to_string(&self.path);
+    pub fn list(&mut self) -> Result<Vec<Bookmark>, Error> {
+        if self.lock.is_none() {
+            self.lock = Some(self.lock_file()?);
+        }
+        let bytes = match self.read()? {
+            Some(bytes) => bytes,
+            None => {
+                self.listed = Some(FileVersion::default());
+                self.listed_content = String::new();
+                return Ok(Vec::new());
+            }
+        };
         let lastmodified = FileStorage::file_modified(&self.path)?;
 
-        match result {
-            Ok(cnt) => Ok(FileStorage::read_file_content(lastmodified, cnt)),
+        self.listed = Some(FileStorage::version(&self.path, &bytes)?);
+        self.listed_content = self.decode(bytes)?;
+        self.read_file_content(lastmodified, self.listed_content.clone())
+    }
+
+    /// The bytes of the file, or `None` if it does not exist
+    fn read(&self) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(Some(bytes)),
             Err(error) => match error.kind() {
-                std::io::ErrorKind::NotFound => Ok(Vec::new()),
+                std::io::ErrorKind::NotFound => Ok(None),
                 _ => Err(Error::IOError(error)),
             },
         }
     }
 
-    fn read_file_content(lastmodified: UnixTime, cnt: String) -> Vec<Bookmark> {
-        cnt.lines()
-            .enumerate()
-            .map(|(i, ln)| {
-                let lastspace = ln.rfind(char::is_whitespace).unwrap_or(0);
+    /// The text of the bytes on disk, after the read filter; an empty file is not filtered
+    fn decode(&self, bytes: Vec<u8>) -> Result<String, Error> {
+        let bytes = match &self.read_filter {
+            Some(filter) if !bytes.is_empty() => filter.filter(&bytes)?,
+            _ => bytes,
+        };
+        String::from_utf8(bytes).map_err(Error::Utf8Error)
+    }
+
+    /// The bytes on disk of the text, after the write filter
+    fn encode(&self, cnt: &str) -> Result<Vec<u8>, Error> {
+        match &self.write_filter {
+            Some(filter) => filter.filter(cnt.as_bytes()),
+            None => Ok(cnt.as_bytes().to_vec()),
+        }
+    }
+
+    fn version(path: &str, bytes: &[u8]) -> Result<FileVersion, Error> {
+        let metadata = fs::metadata(path).map_err(Error::IOError)?;
+        let modified = metadata
+            .modified()
+            .map_err(Error::IOError)?
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map_err(Error::TimeError)?
+            .as_nanos();
+
+        Ok(FileVersion {
+            modified,
+            len: metadata.len(),
+            hash: ring::digest::digest(&ring::digest::SHA256, bytes)
+                .as_ref()
+                .to_vec(),
+        })
+    }
+
+    /// The bytes of the file, after checking that the file did not change since it was listed
+    ///
+    /// A file which was not listed has nothing to check against.
+    pub fn verify(&self) -> Result<Vec<u8>, Error> {
+        let bytes = self.read()?;
+        if let Some(listed) = &self.listed {
+            let version = match &bytes {
+                Some(bytes) => FileStorage::version(&self.path, bytes)?,
+                None => FileVersion::default(),
+            };
+            if listed != &version {
+                return Err(Error::FileChanged(self.path.clone()));
+            }
+        }
+        Ok(bytes.unwrap_or_default())
+    }
+
+    /// The modification time and size of the file
+    pub fn revision(&self) -> Result<Option<String>, Error> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(error) => match error.kind() {
+                std::io::ErrorKind::NotFound => return Ok(None),
+                _ => return Err(Error::IOError(error)),
+            },
+        };
 
-                let (name, url) = ln.split_at(lastspace);
+        let modified = metadata
+            .modified()
+            .map_err(Error::IOError)?
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map_err(Error::TimeError)?
+            .as_nanos();
 
-                Bookmark {
+        Ok(Some(format!("{}:{}", modified, metadata.len())))
+    }
+
+    fn read_file_content(
+        &self,
+        lastmodified: UnixTime,
+        cnt: String,
+    ) -> Result<Vec<Bookmark>, Error> {
+        let mut bookmarks = Vec::new();
+        for (i, ln) in cnt.lines().enumerate() {
+            match self.parse_line(ln) {
+                Ok(Some((name, url))) => bookmarks.push(Bookmark {
                     id: i,
                     name: name.to_owned(),
-                    url: url.trim().to_owned(),
+                    url: url.to_owned(),
                     lastmodified,
+                }),
+                Ok(None) => {}
+                Err(message) => {
+                    let error = Error::MalformedLine(self.path.clone(), i + 1, message);
+                    match self.malformed_lines {
+                        MalformedLines::Skip => eprintln!("Skipping line: {}", error),
+                        MalformedLines::Abort => return Err(error),
+                    }
                 }
-            })
-            .collect()
+            }
+        }
+
+        Ok(bookmarks)
     }
 
     pub fn file_modified(state_file: &str) -> Result<UnixTime, Error> {
@@ -646,7 +2347,7 @@ struct List<T> {
     data: Vec<T>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct NcBookmark {
     id: usize,
     title: String,
@@ -665,6 +2366,10 @@ struct NewNcBookmark {
 #[derive(Serialize, Debug)]
 struct ChangedNcBookmark {
     url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    folders: Option<Vec<i32>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -673,6 +2378,40 @@ struct NcFolder {
     title: String,
     parent_folder: i32,
     children: Vec<NcFolder>,
+    /// Whether the folder is shared with this account without permission to edit it
+    #[serde(default, skip_serializing)]
+    read_only: bool,
+}
+
+/// A folder which another user shares with this account
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NcSharedFolder {
+    id: i32,
+    title: String,
+    /// Older versions leave out the permissions, in which case the folder is writable
+    #[serde(default = "NcSharedFolder::writable")]
+    can_write: bool,
+}
+
+impl NcSharedFolder {
+    fn writable() -> bool {
+        true
+    }
+}
+
+/// An entry of the folder children endpoint
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum NcChild {
+    Bookmark(NcBookmark),
+    Folder(NcChildFolder),
+}
+
+#[derive(Deserialize, Debug)]
+struct NcChildFolder {
+    #[serde(default)]
+    children: Vec<NcChild>,
 }
 
 #[derive(Serialize, Debug)]
@@ -681,6 +2420,65 @@ struct NewNcFolder {
     parent_folder: i32,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct Data<T> {
+    data: T,
+}
+
+/// An entry of the folder child order endpoint
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum NcOrderedChild {
+    Bookmark {
+        id: usize,
+    },
+    Folder {
+        id: i32,
+        #[serde(default, skip_serializing)]
+        children: Vec<NcOrderedChild>,
+    },
+}
+
+/// A single request which applies a change to Nextcloud
+#[derive(Debug)]
+enum Operation {
+    AddBookmark(NewNcBookmark),
+    AddToFolder {
+        folder_id: i32,
+        bookmark_id: usize,
+    },
+    RemoveFromFolder {
+        folder_id: i32,
+        bookmark_id: usize,
+    },
+    EditBookmark(usize, ChangedNcBookmark),
+    DeleteBookmark(usize),
+    /// Imports a Netscape bookmark file into a folder
+    Import {
+        folder_id: i32,
+        document: String,
+    },
+    /// Sets the order of the children of a folder
+    OrderChildren {
+        folder_id: i32,
+        children: Vec<NcOrderedChild>,
+    },
+}
+
+impl Operation {
+    fn bookmark_id(&self) -> Option<usize> {
+        match self {
+            Operation::AddBookmark(_) => None,
+            Operation::Import { .. } => None,
+            Operation::OrderChildren { .. } => None,
+            Operation::AddToFolder { bookmark_id, .. } => Some(*bookmark_id),
+            Operation::RemoveFromFolder { bookmark_id, .. } => Some(*bookmark_id),
+            Operation::EditBookmark(bookmark_id, _) => Some(*bookmark_id),
+            Operation::DeleteBookmark(bookmark_id) => Some(*bookmark_id),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct Item<T> {
     item: T,
@@ -691,100 +2489,889 @@ struct Id<T> {
     id: T,
 }
 
+#[derive(Deserialize, Debug)]
+struct LoginFlow {
+    poll: LoginPoll,
+    login: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginPoll {
+    token: String,
+    endpoint: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LoginCredentials {
+    login_name: String,
+    app_password: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NcShare {
+    #[allow(dead_code)]
+    id: i32,
+}
+
+/// The optional features of the Bookmarks app, which differ between versions
+///
+/// A feature is unknown until the first request to its endpoint, so no requests are spent on
+/// finding them out.
+#[derive(Debug, Default)]
+struct Capabilities {
+    /// `/folder/{id}/hash`
+    hash: Option<bool>,
+    /// `/folder/{id}/children`
+    children: Option<bool>,
+    /// `/folder/{id}/childorder`
+    childorder: Option<bool>,
+    /// `/folder/deleted`
+    trash: Option<bool>,
+    /// `/folder/shared`
+    shared: Option<bool>,
+}
+
+/// The credentials which are sent with every request to Nextcloud
+#[derive(Debug)]
+enum Auth {
+    Basic { username: String, password: String },
+    Bearer(String),
+    Header { name: String, value: String },
+}
+
+trait Authorize {
+    fn authorize(self, auth: &Auth) -> Self;
+}
+
+impl Authorize for reqwest::blocking::RequestBuilder {
+    fn authorize(self, auth: &Auth) -> Self {
+        match auth {
+            Auth::Basic { username, password } => self.basic_auth(username, Some(password)),
+            Auth::Bearer(token) => self.bearer_auth(token),
+            Auth::Header { name, value } => self.header(name, value),
+        }
+    }
+}
+
+/// Accepts exactly the server certificate with the pinned SHA-256 fingerprint
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: Vec<u8>,
+    provider: std::sync::Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+
+        if digest.as_ref() == self.fingerprint.as_slice() {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate does not match the pinned fingerprint".to_owned(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
 #[derive(Debug)]
 struct NextcloudStorage {
+    client: reqwest::blocking::Client,
     url: String,
-    username: String,
-    password: String,
+    auth: Auth,
     folders: Vec<NcFolder>,
+    /// The bookmarks as they were listed, by id, kept up to date while applying changes
+    bookmarks: HashMap<usize, NcBookmark>,
+    remove_empty_folders: bool,
+    concurrency: usize,
+    import_threshold: usize,
+    /// The path of the folder to sync, instead of the whole account
+    root_folder: Option<String>,
+    /// The id of `root_folder`, once it is resolved
+    root: Option<i32>,
+    capabilities: Capabilities,
+    /// Whether the order of the new state is pushed to the child order of the folders
+    push_order: bool,
 }
 
 impl NextcloudStorage {
-    pub fn apply(
-        &mut self,
-        mut changes: Changes,
-        _new_state: &Vec<SBookmark>,
-    ) -> Result<(), Error> {
+    pub fn apply(&mut self, mut changes: Changes, new_state: &[SBookmark]) -> Result<(), Error> {
+        // The synced folder is the root, so paths get its prefix back
         let mut parent = NcFolder {
             title: String::new(),
-            id: -1,
+            id: self.root.unwrap_or(-1),
             children: self.folders.clone(),
             parent_folder: -2,
+            read_only: false,
+        };
+
+        let mut emptied = Vec::new();
+        for (from, to) in changes.renamed.drain(..) {
+            emptied.push(self.rename_folder(&mut parent, &from, &to)?);
+        }
+
+        // Deletes go before creates, so a created url does not collide with a deleted one.
+        // A delete of a url which is created again is a move, which waits for the create.
+        let new_urls: HashSet<Url> = changes
+            .new
+            .values()
+            .map(|bookmark| bookmark.url.clone())
+            .collect();
+        let (moved, deleted): (Vec<_>, Vec<_>) =
+            changes.deleted.iter().partition(|(_, bookmark_id)| {
+                self.bookmarks
+                    .get(bookmark_id)
+                    .is_some_and(|bookmark| new_urls.contains(&bookmark.url))
+            });
+
+        let mut operations = Vec::new();
+        for (name, bookmark_id) in deleted {
+            emptied.append(&mut self.plan_remove(name, *bookmark_id, &mut operations));
+        }
+        self.execute_all(operations)?;
+
+        // The folders are created one by one, before the bookmarks in them
+        let mut operations = Vec::new();
+        let mut created: HashMap<Url, NewNcBookmark> = HashMap::new();
+        // A moved bookmark keeps its id, in the folders claimed by its new paths
+        let moving: HashSet<usize> = moved.iter().map(|(_, bookmark_id)| **bookmark_id).collect();
+        let mut claimed: HashSet<(usize, i32)> = HashSet::new();
+        let mut retitled: HashMap<usize, String> = HashMap::new();
+        // Created bookmarks are added at the end of their folder
+        let reordered = changes.reordered || !changes.new.is_empty();
+        for (_, bookmark) in changes.new.drain() {
+            let exploded: Vec<&str> = bookmark.name.split('/').collect();
+            let len = exploded.len();
+            let folder_id = self.ensure_folder(&mut parent, &exploded[..len - 1])?;
+
+            // Nextcloud allows a url only once, so an existing url gets a folder membership
+            let existing = self
+                .find_bookmark(&bookmark.url)
+                .map(|nc_bookmark| (nc_bookmark.id, nc_bookmark.title != exploded[len - 1]));
+            match existing {
+                Some((bookmark_id, other_title)) => {
+                    if other_title && moving.contains(&bookmark_id) {
+                        retitled.insert(bookmark_id, exploded[len - 1].to_string());
+                    } else if other_title {
+                        eprintln!(
+                            "The url of `{}` already exists in Nextcloud with another title",
+                            bookmark.name
+                        );
+                    }
+                    claimed.insert((bookmark_id, folder_id));
+                    operations.extend(self.plan_add_to_folder(folder_id, bookmark_id));
+                }
+                None => {
+                    // A new url in several folders is created once, in all of them
+                    created
+                        .entry(bookmark.url.clone())
+                        .or_insert_with(|| NewNcBookmark {
+                            url: bookmark.url.clone(),
+                            title: exploded[len - 1].to_string(),
+                            folders: Vec::new(),
+                        })
+                        .folders
+                        .push(folder_id);
+                }
+            }
+        }
+        if created.len() > self.import_threshold {
+            self.execute_all(operations)?;
+            self.import(created.into_values().collect())?;
+        } else {
+            operations.extend(created.into_values().map(Operation::AddBookmark));
+            self.execute_all(operations)?;
+        }
+
+        // An edit sends all folders of the bookmark, so it waits for the folders it leaves
+        let mut operations = Vec::new();
+        for (name, bookmark_id) in moved {
+            let stays = self
+                .folder_of(name)
+                .is_some_and(|folder_id| claimed.contains(&(*bookmark_id, folder_id)));
+            if !stays {
+                emptied.append(&mut self.plan_remove(name, *bookmark_id, &mut operations));
+            }
+        }
+        let removed: HashSet<usize> = operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::DeleteBookmark(bookmark_id) => Some(*bookmark_id),
+                _ => None,
+            })
+            .collect();
+        self.execute_all(operations)?;
+
+        let mut operations: Vec<Operation> = changes
+            .changed
+            .drain()
+            .filter(|(_, bookmark)| !removed.contains(&bookmark.id))
+            .map(|(_, bookmark)| self.plan_edit(bookmark.id, bookmark.url, None))
+            .collect();
+        for (bookmark_id, title) in retitled {
+            let url = match self.bookmarks.get_mut(&bookmark_id) {
+                Some(nc_bookmark) if !removed.contains(&bookmark_id) => {
+                    nc_bookmark.title = title.clone();
+                    nc_bookmark.url.clone()
+                }
+                _ => continue,
+            };
+            operations.push(self.plan_edit(bookmark_id, url, Some(title)));
+        }
+        self.execute_all(operations)?;
+
+        if self.push_order && self.capabilities.childorder == Some(true) && reordered {
+            let operations = self.plan_child_order(&parent, new_state);
+            self.execute_all(operations)?;
+        }
+
+        if self.remove_empty_folders {
+            self.remove_emptied_folders(&parent, emptied)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates many bookmarks with one import per folder, instead of one request per bookmark
+    fn import(&mut self, bookmarks: Vec<NewNcBookmark>) -> Result<(), Error> {
+        let mut by_folder: HashMap<i32, Vec<&NewNcBookmark>> = HashMap::new();
+        for bookmark in bookmarks.iter() {
+            if let Some(folder_id) = bookmark.folders.first() {
+                by_folder.entry(*folder_id).or_default().push(bookmark);
+            }
+        }
+
+        let operations = by_folder
+            .drain()
+            .map(|(folder_id, bookmarks)| Operation::Import {
+                folder_id,
+                document: NextcloudStorage::netscape_document(&bookmarks),
+            })
+            .collect();
+        self.execute_all(operations)?;
+
+        // The import does not report the new ids, so list them
+        self.list()?;
+
+        // A bookmark can only be imported into one folder, the others are memberships
+        let mut operations = Vec::new();
+        for bookmark in bookmarks.iter() {
+            let bookmark_id = match self.find_bookmark(&bookmark.url) {
+                Some(nc_bookmark) => nc_bookmark.id,
+                None => continue,
+            };
+            for folder_id in bookmark.folders.iter().skip(1) {
+                operations.extend(self.plan_add_to_folder(*folder_id, bookmark_id));
+            }
+        }
+        self.execute_all(operations)
+    }
+
+    /// A bookmark file in the Netscape format, as exported by browsers
+    fn netscape_document(bookmarks: &[&NewNcBookmark]) -> String {
+        let escape = |text: &str| {
+            text.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
         };
 
-        for (_, bookmark) in changes.new.drain() {
-            let exploded: Vec<&str> = bookmark.name.split('/').collect();
-            let len = exploded.len();
-            let folder_id = self.ensure_folder(&mut parent, &exploded[..len - 1])?;
+        let mut document = String::from(
+            "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+             <META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n\
+             <TITLE>Bookmarks</TITLE>\n\
+             <H1>Bookmarks</H1>\n\
+             <DL><p>\n",
+        );
+        for bookmark in bookmarks {
+            document += &format!(
+                "    <DT><A HREF=\"{}\">{}</A>\n",
+                escape(&bookmark.url),
+                escape(&bookmark.title)
+            );
+        }
+        document += "</DL><p>\n";
+        document
+    }
+
+    /// Runs the operations concurrently, at most `concurrency` at a time
+    ///
+    /// Every operation is attempted, the errors of all failed operations are returned together.
+    fn execute_all(&mut self, operations: Vec<Operation>) -> Result<(), Error> {
+        let queue = std::sync::Mutex::new(operations.into_iter());
+        let results = std::sync::Mutex::new(Vec::new());
+        let storage = &*self;
+
+        std::thread::scope(|scope| {
+            for _ in 0..storage.concurrency.max(1) {
+                scope.spawn(|| loop {
+                    let operation = match queue.lock().unwrap().next() {
+                        Some(operation) => operation,
+                        None => break,
+                    };
+                    let result = storage.execute(&operation);
+                    results.lock().unwrap().push(result);
+                });
+            }
+        });
+
+        let mut errors = Vec::new();
+        for result in results.into_inner().unwrap() {
+            match result {
+                Ok(Some(created)) => {
+                    self.bookmarks.insert(created.id, created);
+                }
+                Ok(None) => {}
+                Err(error) => errors.push(error),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Operations(errors))
+        }
+    }
+
+    /// Performs a single operation, and returns the bookmark it created, if any
+    fn execute(&self, operation: &Operation) -> Result<Option<NcBookmark>, Error> {
+        let request = match operation {
+            Operation::AddBookmark(new_bookmark) => {
+                let created: Item<NcBookmark> = self
+                    .client
+                    .post(self.url.clone() + "/bookmark")
+                    .authorize(&self.auth)
+                    .json(new_bookmark)
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .map_err(Error::Reqwest)?
+                    .json()
+                    .map_err(Error::Reqwest)?;
+                return Ok(Some(created.item));
+            }
+            Operation::AddToFolder {
+                folder_id,
+                bookmark_id,
+            } => self
+                .client
+                .post(self.membership_url(*folder_id, *bookmark_id)),
+            Operation::RemoveFromFolder {
+                folder_id,
+                bookmark_id,
+            } => self
+                .client
+                .delete(self.membership_url(*folder_id, *bookmark_id)),
+            Operation::EditBookmark(bookmark_id, bookmark) => self
+                .client
+                .put(self.url.clone() + "/bookmark/" + &bookmark_id.to_string())
+                .json(bookmark),
+            Operation::DeleteBookmark(bookmark_id) => self
+                .client
+                .delete(self.url.clone() + "/bookmark/" + &bookmark_id.to_string()),
+            Operation::Import {
+                folder_id,
+                document,
+            } => {
+                let file = reqwest::blocking::multipart::Part::text(document.clone())
+                    .file_name("bookmarks.html")
+                    .mime_str("text/html")
+                    .map_err(Error::Reqwest)?;
+                self.client
+                    .post(self.url.clone() + "/folder/" + &folder_id.to_string() + "/import")
+                    .multipart(reqwest::blocking::multipart::Form::new().part("bm_import", file))
+            }
+            Operation::OrderChildren {
+                folder_id,
+                children,
+            } => self
+                .client
+                .patch(self.url.clone() + "/folder/" + &folder_id.to_string() + "/childorder")
+                .json(&Data { data: children }),
+        };
+
+        request
+            .authorize(&self.auth)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::Reqwest)?;
+
+        Ok(None)
+    }
+
+    /// Puts the children of every folder in the order of the new state
+    fn plan_child_order(&self, root: &NcFolder, new_state: &[SBookmark]) -> Vec<Operation> {
+        let mut orders: Vec<(i32, Vec<NcOrderedChild>)> = Vec::new();
+        let mut positions: HashMap<i32, usize> = HashMap::new();
+        let mut seen: HashSet<(i32, i32)> = HashSet::new();
+        let mut push = |folder_id: i32, child: NcOrderedChild| {
+            let position = *positions.entry(folder_id).or_insert_with(|| {
+                orders.push((folder_id, Vec::new()));
+                orders.len() - 1
+            });
+            orders[position].1.push(child);
+        };
+
+        'bookmarks: for bookmark in new_state {
+            let exploded: Vec<&str> = bookmark.name.split('/').collect();
+            let mut folder = root;
+            for part in &exploded[..exploded.len() - 1] {
+                let child = match folder.children.iter().find(|child| &child.title == part) {
+                    Some(child) => child,
+                    None => continue 'bookmarks,
+                };
+                if seen.insert((folder.id, child.id)) {
+                    push(
+                        folder.id,
+                        NcOrderedChild::Folder {
+                            id: child.id,
+                            children: Vec::new(),
+                        },
+                    );
+                }
+                folder = child;
+            }
+
+            if let Some(nc_bookmark) = self.find_bookmark(&bookmark.url) {
+                push(folder.id, NcOrderedChild::Bookmark { id: nc_bookmark.id });
+            }
+        }
+
+        // Subfolders without bookmarks keep their place at the end
+        for (folder_id, children) in orders.iter_mut() {
+            let subfolders = NextcloudStorage::find_folder(std::slice::from_ref(root), *folder_id)
+                .map_or(&[][..], |folder| &folder.children[..]);
+            for subfolder in subfolders {
+                if seen.insert((*folder_id, subfolder.id)) {
+                    children.push(NcOrderedChild::Folder {
+                        id: subfolder.id,
+                        children: Vec::new(),
+                    });
+                }
+            }
+        }
+
+        // Read-only shares keep the order of their owner
+        orders
+            .into_iter()
+            .filter(|(folder_id, _)| {
+                !NextcloudStorage::find_folder(std::slice::from_ref(root), *folder_id)
+                    .is_some_and(|folder| folder.read_only)
+            })
+            .map(|(folder_id, children)| Operation::OrderChildren {
+                folder_id,
+                children,
+            })
+            .collect()
+    }
+
+    fn membership_url(&self, folder_id: i32, bookmark_id: usize) -> String {
+        self.url.clone()
+            + "/folder/"
+            + &folder_id.to_string()
+            + "/bookmarks/"
+            + &bookmark_id.to_string()
+    }
+
+    /// Deletes the folders which became empty because bookmarks were removed from them
+    ///
+    /// Folders which were empty before are never candidates, and folders which are shared or
+    /// published are kept.
+    fn remove_emptied_folders(
+        &self,
+        root: &NcFolder,
+        mut candidates: Vec<i32>,
+    ) -> Result<(), Error> {
+        let mut deleted: HashSet<i32> = HashSet::new();
+
+        while let Some(folder_id) = candidates.pop() {
+            if deleted.contains(&folder_id) {
+                continue;
+            }
+            let folder = match NextcloudStorage::find_folder(&root.children, folder_id) {
+                Some(folder) => folder,
+                None => continue,
+            };
+
+            let has_children = folder
+                .children
+                .iter()
+                .any(|child| !deleted.contains(&child.id));
+            let has_bookmarks = self
+                .bookmarks
+                .values()
+                .any(|bookmark| bookmark.folders.contains(&folder_id));
+            if has_children || has_bookmarks {
+                continue;
+            }
+
+            // Ask the server too, it also knows about bookmarks we could not list
+            if self.folder_count(folder_id)? > 0 || self.folder_is_shared(folder_id)? {
+                continue;
+            }
+
+            self.delete_folder(folder_id)?;
+            deleted.insert(folder_id);
+            candidates.push(folder.parent_folder);
+        }
+
+        Ok(())
+    }
+
+    /// Moves the folder at path `from` to path `to`, and returns the id of its old parent
+    fn rename_folder(&self, root: &mut NcFolder, from: &str, to: &str) -> Result<i32, Error> {
+        let from_parts: Vec<&str> = from.split('/').collect();
+        let to_parts: Vec<&str> = to.split('/').collect();
+        let len = to_parts.len();
+
+        let mut folder = NextcloudStorage::take_folder(root, &from_parts)
+            .ok_or_else(|| Error::FolderNotFound(from.to_owned()))?;
+        let old_parent = folder.parent_folder;
+        let parent_folder = self.ensure_folder(root, &to_parts[..len - 1])?;
+
+        let folder_url = self.url.clone() + "/folder/" + &folder.id.to_string();
+        self.client
+            .put(&folder_url)
+            .authorize(&self.auth)
+            .json(&NewNcFolder {
+                title: to_parts[len - 1].to_string(),
+                parent_folder,
+            })
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::Reqwest)?;
+
+        folder.title = to_parts[len - 1].to_string();
+        folder.parent_folder = parent_folder;
+        if let Some(new_parent) = NextcloudStorage::find_folder_mut(root, parent_folder) {
+            new_parent.children.push(folder);
+        }
+
+        Ok(old_parent)
+    }
+
+    /// Removes the folder at the given path from the tree
+    fn take_folder(folder: &mut NcFolder, parts: &[&str]) -> Option<NcFolder> {
+        match parts.split_first() {
+            None => None,
+            Some((head, [])) => {
+                let index = folder.children.iter().position(|c| &c.title == head)?;
+                Some(folder.children.remove(index))
+            }
+            Some((head, rest)) => {
+                let child = folder.children.iter_mut().find(|c| &c.title == head)?;
+                NextcloudStorage::take_folder(child, rest)
+            }
+        }
+    }
 
-            self.add_bookmark(folder_id, bookmark)?;
+    fn find_folder_mut(folder: &mut NcFolder, id: i32) -> Option<&mut NcFolder> {
+        if folder.id == id {
+            return Some(folder);
         }
+        folder
+            .children
+            .iter_mut()
+            .find_map(|child| NextcloudStorage::find_folder_mut(child, id))
+    }
 
-        for (_, bookmark) in changes.changed.drain() {
-            self.edit_bookmark(bookmark)?;
+    fn folder_ids(folders: &[NcFolder], ids: &mut HashSet<i32>) {
+        for folder in folders {
+            ids.insert(folder.id);
+            NextcloudStorage::folder_ids(&folder.children, ids);
         }
+    }
 
-        for bookmark in changes.deleted.values() {
-            self.delete_bookmark(*bookmark)?;
+    fn find_folder(folders: &[NcFolder], id: i32) -> Option<&NcFolder> {
+        for folder in folders {
+            if folder.id == id {
+                return Some(folder);
+            } else if let Some(child) = NextcloudStorage::find_folder(&folder.children, id) {
+                return Some(child);
+            }
         }
+        None
+    }
 
-        Ok(())
+    fn folder_count(&self, folder_id: i32) -> Result<usize, Error> {
+        let count_url = self.url.clone() + "/folder/" + &folder_id.to_string() + "/count";
+        let count: Item<usize> = self
+            .client
+            .get(&count_url)
+            .authorize(&self.auth)
+            .send()
+            .map_err(Error::Reqwest)?
+            .json()
+            .map_err(Error::Reqwest)?;
+
+        Ok(count.item)
     }
 
-    fn add_bookmark(&self, folder_id: i32, bookmark: Bookmark) -> Result<(), Error> {
-        let lastslash = bookmark
-            .name
-            .rfind(|c| c == '/')
-            .map(|x| x + 1)
-            .unwrap_or(0);
-        let (_, title) = bookmark.name.split_at(lastslash);
+    /// Whether the folder is shared with other users or published with a public link
+    fn folder_is_shared(&self, folder_id: i32) -> Result<bool, Error> {
+        let folder_url = self.url.clone() + "/folder/" + &folder_id.to_string();
+        let shares: List<NcShare> = self
+            .client
+            .get(folder_url.clone() + "/shares")
+            .authorize(&self.auth)
+            .send()
+            .map_err(Error::Reqwest)?
+            .json()
+            .map_err(Error::Reqwest)?;
+        if !shares.data.is_empty() {
+            return Ok(true);
+        }
 
-        let new_bookmark = NewNcBookmark {
-            url: bookmark.url,
-            title: title.to_string(),
-            folders: vec![folder_id],
-        };
+        // Without a public token, this responds with an error status
+        let public_token = self
+            .client
+            .get(folder_url + "/publictoken")
+            .authorize(&self.auth)
+            .send()
+            .map_err(Error::Reqwest)?;
 
-        let client = reqwest::blocking::Client::new();
+        Ok(public_token.status().is_success())
+    }
 
-        let bookmark_url = self.url.clone() + "/bookmark";
-        let _ = client
-            .post(&bookmark_url)
-            .basic_auth(&self.username, Some(&self.password))
-            .json(&new_bookmark)
+    fn delete_folder(&self, folder_id: i32) -> Result<(), Error> {
+        let folder_url = self.url.clone() + "/folder/" + &folder_id.to_string();
+        self.client
+            .delete(&folder_url)
+            .authorize(&self.auth)
             .send()
+            .and_then(|response| response.error_for_status())
             .map_err(Error::Reqwest)?;
 
         Ok(())
     }
 
-    fn edit_bookmark(&self, bookmark: Bookmark) -> Result<(), Error> {
-        let updated_bookmark = ChangedNcBookmark { url: bookmark.url };
+    /// Whether the folders of the bookmark at the given path may be edited, which warns if not
+    fn writable(&self, name: &str) -> bool {
+        let exploded: Vec<&str> = name.split('/').collect();
+        let mut folders = &self.folders;
+        for part in &exploded[..exploded.len() - 1] {
+            match folders.iter().find(|folder| &folder.title == part) {
+                Some(folder) if folder.read_only => {
+                    eprintln!(
+                        "`{}` is in a read-only shared folder, so it is not changed in Nextcloud",
+                        name
+                    );
+                    return false;
+                }
+                Some(folder) => folders = &folder.children,
+                None => return true,
+            }
+        }
+        true
+    }
 
-        let client = reqwest::blocking::Client::new();
+    /// Adds the folders which other users share with this account to the folder tree.
+    /// Returns the ids of the shared folders which were not yet in the tree.
+    fn add_shared_folders(&mut self, root: i32) -> Result<Vec<i32>, Error> {
+        if self.capabilities.shared == Some(false) {
+            return Ok(Vec::new());
+        }
 
-        let bookmark_url = self.url.clone() + "/bookmark/" + &bookmark.id.to_string();
-        let _ = client
-            .put(&bookmark_url)
-            .basic_auth(&self.username, Some(&self.password))
-            .json(&updated_bookmark)
-            .send()
-            .map_err(Error::Reqwest)?;
+        let shared_url = self.url.clone() + "/folder/shared";
+        let shared: Option<List<NcSharedFolder>> =
+            self.fetch_optional(self.client.get(&shared_url))?;
+        self.capabilities.shared = Some(shared.is_some());
+        let shared = match shared {
+            Some(shared) => shared,
+            None => return Ok(Vec::new()),
+        };
 
-        Ok(())
+        let mut added = Vec::new();
+        for shared_folder in shared.data {
+            let existing = self
+                .folders
+                .iter_mut()
+                .find_map(|folder| NextcloudStorage::find_folder_mut(folder, shared_folder.id));
+
+            match existing {
+                Some(folder) => NextcloudStorage::set_read_only(folder, !shared_folder.can_write),
+                // Shares are only placed at the top when the whole account is synced
+                None if root == -1 => {
+                    let mut folder = NcFolder {
+                        id: shared_folder.id,
+                        title: shared_folder.title,
+                        parent_folder: root,
+                        children: self.fetch_folders(shared_folder.id)?,
+                        read_only: false,
+                    };
+                    NextcloudStorage::set_read_only(&mut folder, !shared_folder.can_write);
+                    added.push(folder.id);
+                    self.folders.push(folder);
+                }
+                None => {}
+            }
+        }
+
+        Ok(added)
     }
 
-    fn delete_bookmark(&self, bookmark_id: usize) -> Result<(), Error> {
-        let client = reqwest::blocking::Client::new();
+    fn set_read_only(folder: &mut NcFolder, read_only: bool) {
+        folder.read_only = folder.read_only || read_only;
+        for child in folder.children.iter_mut() {
+            NextcloudStorage::set_read_only(child, read_only);
+        }
+    }
 
-        let bookmark_url = self.url.clone() + "/bookmark/" + &bookmark_id.to_string();
-        let _ = client
-            .delete(&bookmark_url)
-            .basic_auth(&self.username, Some(&self.password))
+    /// The public link of the folder at the given path, which creates a public token if needed
+    pub fn public_link(&mut self, path: &str) -> Result<String, Error> {
+        let root = self.resolve_root()?;
+        self.folders = self.fetch_folders(root)?;
+        self.add_shared_folders(root)?;
+
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        let folder_id = NextcloudStorage::folder_id(&self.folders, root, &parts)
+            .ok_or_else(|| Error::FolderNotFound(path.to_owned()))?;
+
+        let token_url = self.url.clone() + "/folder/" + &folder_id.to_string() + "/publictoken";
+        let existing = self
+            .client
+            .get(&token_url)
+            .authorize(&self.auth)
             .send()
             .map_err(Error::Reqwest)?;
 
-        Ok(())
+        let token: Item<String> = if existing.status().is_success() {
+            existing.json().map_err(Error::Reqwest)?
+        } else {
+            self.client
+                .post(&token_url)
+                .authorize(&self.auth)
+                .send()
+                .map_err(Error::Reqwest)?
+                .error_for_status()
+                .map_err(Error::Reqwest)?
+                .json()
+                .map_err(Error::Reqwest)?
+        };
+
+        Ok(NextcloudStorage::server_url(&self.url)?.to_owned()
+            + "/index.php/apps/bookmarks/public/"
+            + &token.item)
+    }
+
+    fn find_bookmark(&self, url: &str) -> Option<&NcBookmark> {
+        self.bookmarks.values().find(|bookmark| bookmark.url == url)
+    }
+
+    fn plan_add_to_folder(&mut self, folder_id: i32, bookmark_id: usize) -> Option<Operation> {
+        let bookmark = self.bookmarks.get_mut(&bookmark_id)?;
+        if bookmark.folders.contains(&folder_id) {
+            return None;
+        }
+        bookmark.folders.push(folder_id);
+
+        Some(Operation::AddToFolder {
+            folder_id,
+            bookmark_id,
+        })
+    }
+
+    /// Removes the bookmark from the folder of `name`, or deletes it if that is its only folder
+    ///
+    /// Returns the folders which the bookmark was removed from.
+    fn plan_remove(
+        &mut self,
+        name: &str,
+        bookmark_id: usize,
+        operations: &mut Vec<Operation>,
+    ) -> Vec<i32> {
+        let folder_id = self.folder_of(name);
+
+        let folders = self
+            .bookmarks
+            .get(&bookmark_id)
+            .map(|bookmark| bookmark.folders.clone())
+            .unwrap_or_default();
+
+        match folder_id {
+            Some(folder_id) if folders.len() > 1 && folders.contains(&folder_id) => {
+                if let Some(bookmark) = self.bookmarks.get_mut(&bookmark_id) {
+                    bookmark.folders.retain(|id| *id != folder_id);
+                }
+                operations.push(Operation::RemoveFromFolder {
+                    folder_id,
+                    bookmark_id,
+                });
+                vec![folder_id]
+            }
+            _ => {
+                // Deleting the bookmark makes the other operations on it obsolete
+                operations.retain(|operation| operation.bookmark_id() != Some(bookmark_id));
+                operations.push(Operation::DeleteBookmark(bookmark_id));
+                self.bookmarks.remove(&bookmark_id);
+                folders
+            }
+        }
+    }
+
+    /// The id of the folder of the bookmark at the given path, if it exists
+    fn folder_of(&self, name: &str) -> Option<i32> {
+        let exploded: Vec<&str> = name.split('/').collect();
+        let len = exploded.len();
+        NextcloudStorage::folder_id(&self.folders, self.root.unwrap_or(-1), &exploded[..len - 1])
+    }
+
+    fn plan_edit(&self, bookmark_id: usize, url: Url, title: Option<String>) -> Operation {
+        // Send the folders along, so the edit keeps all folder memberships
+        let folders = self
+            .bookmarks
+            .get(&bookmark_id)
+            .map(|nc_bookmark| nc_bookmark.folders.clone());
+
+        Operation::EditBookmark(
+            bookmark_id,
+            ChangedNcBookmark {
+                url,
+                title,
+                folders,
+            },
+        )
     }
 
     fn ensure_folder(&self, folder: &mut NcFolder, parts: &[&str]) -> Result<i32, Error> {
@@ -794,7 +3381,7 @@ impl NextcloudStorage {
                     return self.ensure_folder(child, &parts[1..]);
                 }
             }
-            return self.add_subfolders(folder, &parts);
+            self.add_subfolders(folder, parts)
         } else {
             Ok(folder.id)
         }
@@ -818,6 +3405,7 @@ impl NextcloudStorage {
                 parent_folder,
                 title: title.to_string(),
                 children: Vec::new(),
+                read_only: false,
             });
             parent_folder = id;
         }
@@ -834,12 +3422,11 @@ impl NextcloudStorage {
     }
 
     fn add_subfolder(&self, folder: NewNcFolder) -> Result<i32, Error> {
-        let client = reqwest::blocking::Client::new();
-
         let folder_url = self.url.clone() + "/folder";
-        let result: Item<Id<i32>> = client
+        let result: Item<Id<i32>> = self
+            .client
             .post(&folder_url)
-            .basic_auth(&self.username, Some(&self.password))
+            .authorize(&self.auth)
             .json(&folder)
             .send()
             .map_err(Error::Reqwest)?
@@ -849,58 +3436,522 @@ impl NextcloudStorage {
         Ok(result.item.id)
     }
 
-    pub fn new(url: String, username: String, password: String) -> Result<NextcloudStorage, Error> {
-        let client = reqwest::blocking::Client::new();
+    /// The http client, configured with the TLS options of the storage
+    pub fn client(cfg: &StorageConfig) -> Result<reqwest::blocking::Client, Error> {
+        let mut builder = reqwest::blocking::Client::builder();
+
+        if let Some(proxy_cfg) = &cfg.proxy {
+            // Ignore the proxy environment variables
+            builder = builder.no_proxy();
+
+            if let Some(url) = &proxy_cfg.url {
+                let mut proxy = reqwest::Proxy::all(url).map_err(Error::Reqwest)?;
+
+                if let Some(username) = &proxy_cfg.username {
+                    let password = match &proxy_cfg.password {
+                        Some(password) => password.value()?,
+                        None => String::new(),
+                    };
+                    proxy = proxy.basic_auth(&username.value()?, &password);
+                }
+
+                let no_proxy = reqwest::NoProxy::from_string(&proxy_cfg.no_proxy.join(","));
+                builder = builder.proxy(proxy.no_proxy(no_proxy));
+            }
+        }
+
+        // Pinning replaces the certificate authorities, so it needs its own TLS configuration
+        if let Some(fingerprint) = &cfg.certificate_fingerprint {
+            let tls = NextcloudStorage::pinned_tls(cfg, fingerprint)?;
+            return builder
+                .use_preconfigured_tls(tls)
+                .build()
+                .map_err(Error::Reqwest);
+        }
+
+        if let Some(ca_file) = &cfg.ca_file {
+            let pem = fs::read(ca_file).map_err(Error::IOError)?;
+            for certificate in
+                reqwest::Certificate::from_pem_bundle(&pem).map_err(Error::Reqwest)?
+            {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let Some(certificate) = &cfg.client_certificate {
+            let key = cfg
+                .client_key
+                .as_ref()
+                .ok_or(Error::MissingConfig("client_key"))?;
+            let certificate = fs::read(certificate).map_err(Error::IOError)?;
+            let key = fs::read(key).map_err(Error::IOError)?;
+            let identity =
+                reqwest::Identity::from_pkcs8_pem(&certificate, &key).map_err(Error::Reqwest)?;
+            builder = builder.identity(identity);
+        }
+
+        if cfg.danger_accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        builder.build().map_err(Error::Reqwest)
+    }
+
+    fn pinned_tls(cfg: &StorageConfig, fingerprint: &str) -> Result<rustls::ClientConfig, Error> {
+        let tls_error = |error: &dyn std::fmt::Display| Error::Tls(error.to_string());
+
+        // The pin is the only check, so other trust settings would be silently ignored
+        if cfg.ca_file.is_some() || cfg.danger_accept_invalid_certs {
+            return Err(Error::Tls(
+                "`certificate_fingerprint` can not be combined with `ca_file` or `danger_accept_invalid_certs`"
+                    .to_owned(),
+            ));
+        }
+
+        let hex: Vec<char> = fingerprint.chars().filter(|c| *c != ':').collect();
+        let fingerprint = Some(hex)
+            .filter(|hex| hex.len() == 64 && hex.iter().all(char::is_ascii_hexdigit))
+            .map(|hex| {
+                hex.chunks(2)
+                    .map(|byte| u8::from_str_radix(&byte.iter().collect::<String>(), 16).unwrap())
+                    .collect::<Vec<u8>>()
+            })
+            .ok_or_else(|| Error::Tls("invalid SHA-256 certificate fingerprint".to_owned()))?;
+
+        let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| tls_error(&e))?
+            .dangerous()
+            .with_custom_certificate_verifier(std::sync::Arc::new(PinnedCertificate {
+                fingerprint,
+                provider,
+            }));
+
+        match &cfg.client_certificate {
+            Some(certificate) => {
+                use rustls::pki_types::pem::PemObject;
+
+                let key = cfg
+                    .client_key
+                    .as_ref()
+                    .ok_or(Error::MissingConfig("client_key"))?;
+                let certificates = rustls::pki_types::CertificateDer::pem_file_iter(certificate)
+                    .map_err(|e| tls_error(&e))?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| tls_error(&e))?;
+                let key = rustls::pki_types::PrivateKeyDer::from_pem_file(key)
+                    .map_err(|e| tls_error(&e))?;
+
+                builder
+                    .with_client_auth_cert(certificates, key)
+                    .map_err(|e| tls_error(&e))
+            }
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+
+    pub fn new(cfg: &StorageConfig, url: String, auth: Auth) -> Result<NextcloudStorage, Error> {
+        Ok(NextcloudStorage {
+            client: NextcloudStorage::client(cfg)?,
+            url,
+            auth,
+            folders: Vec::new(),
+            bookmarks: HashMap::new(),
+            remove_empty_folders: cfg.remove_empty_folders,
+            concurrency: cfg.concurrency.unwrap_or(4),
+            import_threshold: cfg.import_threshold.unwrap_or(100),
+            root_folder: cfg.root_folder.clone(),
+            root: None,
+            capabilities: Capabilities::default(),
+            push_order: false,
+        })
+    }
+
+    /// Finds out which optional endpoints the server has
+    ///
+    /// Only `doctor` asks for all features at once, a sync finds them out as it goes.
+    fn probe(&mut self) -> Result<(), Error> {
+        let get = |path: &str| {
+            self.client
+                .get(self.url.clone() + path)
+                .authorize(&self.auth)
+                .send()
+                .map_err(Error::Reqwest)
+                .and_then(|response| NextcloudStorage::route_exists(&response))
+                .map(Some)
+        };
+
+        self.capabilities = Capabilities {
+            hash: get("/folder/-1/hash")?,
+            children: get("/folder/-1/children")?,
+            childorder: get("/folder/-1/childorder")?,
+            trash: get("/folder/deleted")?,
+            shared: get("/folder/shared")?,
+        };
+        Ok(())
+    }
+
+    /// An unknown route is not found, or not allowed when it exists for other methods
+    ///
+    /// Other errors, like wrong credentials, say nothing about the route and fail.
+    fn route_exists(response: &reqwest::blocking::Response) -> Result<bool, Error> {
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::METHOD_NOT_ALLOWED => Ok(false),
+            reqwest::StatusCode::LOCKED => Ok(true),
+            _ => response
+                .error_for_status_ref()
+                .map(|_| true)
+                .map_err(Error::Reqwest),
+        }
+    }
+
+    /// Sends a request to an endpoint which older versions of the Bookmarks app lack, and
+    /// returns `None` if the server does not have it
+    fn fetch_optional<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<Option<T>, Error> {
+        let response = request
+            .authorize(&self.auth)
+            .send()
+            .map_err(Error::Reqwest)?;
+        if !NextcloudStorage::route_exists(&response)? {
+            return Ok(None);
+        }
+        response.json().map(Some).map_err(Error::Reqwest)
+    }
+
+    /// Finds out whether the server has the client lock of `/lock`
+    ///
+    /// Taking the lock is the only way to find it, and it blocks other clients of the account
+    /// while it is held. So only `doctor` probes it, and releases it right away.
+    fn probe_lock(&self) -> Result<bool, Error> {
+        let lock_url = self.url.clone() + "/lock";
+        let lock = self
+            .client
+            .post(&lock_url)
+            .authorize(&self.auth)
+            .send()
+            .map_err(Error::Reqwest)?;
+        if lock.status().is_success() {
+            self.client
+                .delete(&lock_url)
+                .authorize(&self.auth)
+                .send()
+                .map_err(Error::Reqwest)?;
+        }
+
+        NextcloudStorage::route_exists(&lock)
+    }
+
+    /// Runs Nextcloud Login Flow v2 for the server of the bookmarks api `url`
+    ///
+    /// The user grants access in the browser, meanwhile the server is polled for the app
+    /// password.
+    pub fn login_flow(
+        client: &reqwest::blocking::Client,
+        url: &str,
+    ) -> Result<LoginCredentials, Error> {
+        let server = NextcloudStorage::server_url(url)?;
+
+        let flow: LoginFlow = client
+            .post(server.to_owned() + "/index.php/login/v2")
+            .header(reqwest::header::USER_AGENT, "nc-bookmark-sync")
+            .send()
+            .map_err(Error::Reqwest)?
+            .json()
+            .map_err(Error::Reqwest)?;
+
+        println!("Open the following url to log in:\n{}", flow.login);
+        let opener = if cfg!(target_os = "macos") {
+            "open"
+        } else {
+            "xdg-open"
+        };
+        let _ = std::process::Command::new(opener)
+            .arg(&flow.login)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn();
+
+        // The token is valid for 20 minutes
+        for _ in 0..600 {
+            std::thread::sleep(std::time::Duration::from_secs(2));
+
+            let response = client
+                .post(&flow.poll.endpoint)
+                .form(&[("token", &flow.poll.token)])
+                .send()
+                .map_err(Error::Reqwest)?;
+
+            if response.status().is_success() {
+                return response.json().map_err(Error::Reqwest);
+            }
+        }
+
+        Err(Error::LoginTimeout)
+    }
+
+    /// The address of the Nextcloud server, without the path of the Bookmarks API
+    fn server_url(url: &str) -> Result<&str, Error> {
+        let end = url
+            .find("/index.php/")
+            .or_else(|| url.find("/apps/bookmarks"))
+            .ok_or(Error::MissingConfig("url"))?;
+
+        Ok(&url[..end])
+    }
+
+    /// The hash of the root folder, or `None` if the server could not compute it
+    pub fn revision(&mut self) -> Result<Option<String>, Error> {
+        if self.capabilities.hash == Some(false) {
+            return Ok(None);
+        }
+        let root = self.resolve_root()?;
+
+        let hash_url = self.url.clone() + "/folder/" + &root.to_string() + "/hash";
+        let hash: Option<Data<String>> = self.fetch_optional(self.client.get(&hash_url))?;
+        self.capabilities.hash = Some(hash.is_some());
+        Ok(hash.map(|hash| hash.data))
+    }
+
+    /// The id of the folder which is synced, which is created if it does not exist yet
+    fn resolve_root(&mut self) -> Result<i32, Error> {
+        if let Some(root) = self.root {
+            return Ok(root);
+        }
+
+        let root = match self.root_folder.clone() {
+            None => -1,
+            Some(path) => {
+                let mut tree = NcFolder {
+                    title: String::new(),
+                    id: -1,
+                    children: self.fetch_folders(-1)?,
+                    parent_folder: -2,
+                    read_only: false,
+                };
+                let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+                self.ensure_folder(&mut tree, &parts)?
+            }
+        };
+
+        self.root = Some(root);
+        Ok(root)
+    }
 
-        let folder_url = url.clone() + "/folder";
-        let folders: List<NcFolder> = client
+    /// The folder tree below the given folder
+    fn fetch_folders(&self, root: i32) -> Result<Vec<NcFolder>, Error> {
+        let folder_url = self.url.clone() + "/folder";
+        let folders: List<NcFolder> = self
+            .client
             .get(&folder_url)
-            .basic_auth(&username, Some(&password))
+            .query(&[("root", root), ("layers", -1)])
+            .authorize(&self.auth)
             .send()
             .map_err(Error::Reqwest)?
             .json()
             .map_err(Error::Reqwest)?;
 
-        Ok(NextcloudStorage {
-            url,
-            username,
-            password,
-            folders: folders.data,
-        })
+        Ok(folders.data)
     }
 
-    pub fn list(&self) -> Result<Vec<Bookmark>, Error> {
-        let client = reqwest::blocking::Client::new();
+    /// All bookmarks below the given folder
+    fn fetch_bookmarks(&mut self, root: i32) -> Result<Vec<NcBookmark>, Error> {
+        let children: Option<List<NcChild>> =
+            if root == -1 || self.capabilities.children == Some(false) {
+                None
+            } else {
+                let children_url = self.url.clone() + "/folder/" + &root.to_string() + "/children";
+                let children =
+                    self.fetch_optional(self.client.get(&children_url).query(&[("layers", -1)]))?;
+                self.capabilities.children = Some(children.is_some());
+                children
+            };
+
+        let children = match children {
+            Some(children) => children,
+            None => return self.fetch_all_bookmarks(root),
+        };
+
+        // A bookmark in several folders of the subtree occurs several times
+        let mut bookmarks: HashMap<usize, NcBookmark> = HashMap::new();
+        let mut pending = children.data;
+        while let Some(child) = pending.pop() {
+            match child {
+                NcChild::Bookmark(bookmark) => {
+                    bookmarks.insert(bookmark.id, bookmark);
+                }
+                NcChild::Folder(mut folder) => pending.append(&mut folder.children),
+            }
+        }
+
+        Ok(bookmarks.into_values().collect())
+    }
 
+    /// All bookmarks below the given folder, filtered from the list of all bookmarks
+    fn fetch_all_bookmarks(&self, root: i32) -> Result<Vec<NcBookmark>, Error> {
         let bookmark_url = self.url.clone() + "/bookmark";
-        let mut bookmarks: List<NcBookmark> = client
+        let bookmarks: List<NcBookmark> = self
+            .client
             .get(&bookmark_url)
             .query(&[("limit", 10000)])
-            .basic_auth(&self.username, Some(&self.password))
+            .authorize(&self.auth)
             .send()
             .map_err(Error::Reqwest)?
             .json()
             .map_err(Error::Reqwest)?;
 
+        if root == -1 {
+            return Ok(bookmarks.data);
+        }
+
+        // Without the children endpoint, the bookmarks outside the subtree are left out
+        let mut subtree = HashSet::new();
+        subtree.insert(root);
+        NextcloudStorage::folder_ids(&self.folders, &mut subtree);
         Ok(bookmarks
             .data
+            .into_iter()
+            .filter(|bookmark| bookmark.folders.iter().any(|id| subtree.contains(id)))
+            .collect())
+    }
+
+    pub fn list(&mut self) -> Result<Vec<Bookmark>, Error> {
+        // A root folder remembered from the previous run may be gone, so it is looked up again
+        if self.root_folder.is_some() {
+            self.root = None;
+        }
+        let root = self.resolve_root()?;
+        self.folders = self.fetch_folders(root)?;
+        let shared = self.add_shared_folders(root)?;
+        let mut bookmarks = self.fetch_bookmarks(root)?;
+
+        // The bookmarks of other users are not always listed with the own bookmarks
+        let mut known: HashSet<usize> = bookmarks.iter().map(|bookmark| bookmark.id).collect();
+        for folder_id in shared {
+            for bookmark in self.fetch_bookmarks(folder_id)? {
+                if known.insert(bookmark.id) {
+                    bookmarks.push(bookmark);
+                }
+            }
+        }
+
+        // A bookmark is listed once for every folder it is in
+        let mut result: Vec<Bookmark> = bookmarks
+            .iter()
+            .flat_map(|bookmark| {
+                self.bookmark_paths(bookmark)
+                    .into_iter()
+                    .map(move |name| Bookmark {
+                        id: bookmark.id,
+                        name,
+                        url: bookmark.url.clone(),
+                        lastmodified: bookmark.lastmodified,
+                    })
+            })
+            .collect();
+
+        self.bookmarks = bookmarks
             .drain(..)
-            .map(|bookmark| {
-                let mut name = bookmark.title;
+            .map(|bookmark| (bookmark.id, bookmark))
+            .collect();
+
+        // The bookmarks are listed in the order of the children of each folder
+        if let Some(children) = self.fetch_child_order(root)? {
+            let mut positions = HashMap::new();
+            self.child_positions(&children, "", &mut positions);
+            result.sort_by_key(|bookmark| {
+                positions.get(&bookmark.name).copied().unwrap_or(usize::MAX)
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// The order of the children below the folder, or `None` if the server keeps no order
+    fn fetch_child_order(&mut self, root: i32) -> Result<Option<Vec<NcOrderedChild>>, Error> {
+        if self.capabilities.childorder == Some(false) {
+            return Ok(None);
+        }
+
+        let order_url = self.url.clone() + "/folder/" + &root.to_string() + "/childorder";
+        let children: Option<List<NcOrderedChild>> =
+            self.fetch_optional(self.client.get(&order_url).query(&[("layers", -1)]))?;
+        self.capabilities.childorder = Some(children.is_some());
+
+        Ok(children.map(|children| children.data))
+    }
 
-                if let Some(folder_id) = bookmark.folders.first() {
-                    if let Some(path) = NextcloudStorage::folder_path(&self.folders, *folder_id) {
-                        name = path + "/" + &name;
+    /// Numbers the paths of the bookmarks in the order in which they appear in the folders
+    fn child_positions(
+        &self,
+        children: &[NcOrderedChild],
+        prefix: &str,
+        positions: &mut HashMap<Path, usize>,
+    ) {
+        for child in children {
+            match child {
+                NcOrderedChild::Bookmark { id } => {
+                    if let Some(bookmark) = self.bookmarks.get(id) {
+                        let position = positions.len();
+                        positions
+                            .entry(prefix.to_owned() + &bookmark.title)
+                            .or_insert(position);
+                    }
+                }
+                NcOrderedChild::Folder { id, children } => {
+                    if let Some(folder) = NextcloudStorage::find_folder(&self.folders, *id) {
+                        let prefix = prefix.to_owned() + &folder.title + "/";
+                        self.child_positions(children, &prefix, positions);
                     }
                 }
-                Bookmark {
-                    id: bookmark.id,
-                    name: name,
-                    url: bookmark.url,
-                    lastmodified: bookmark.lastmodified,
+            }
+        }
+    }
+
+    /// The paths of the bookmark relative to the synced folder
+    fn bookmark_paths(&self, bookmark: &NcBookmark) -> Vec<Path> {
+        let root = self.root.unwrap_or(-1);
+        let whole_account = root == -1;
+
+        // Folders outside of the synced folder are left out
+        let mut paths: Vec<Path> = bookmark
+            .folders
+            .iter()
+            .filter_map(|folder_id| {
+                if *folder_id == root {
+                    return Some(bookmark.title.clone());
+                }
+                match NextcloudStorage::folder_path(&self.folders, *folder_id) {
+                    Some(path) => Some(path + "/" + &bookmark.title),
+                    None if whole_account => Some(bookmark.title.clone()),
+                    None => None,
                 }
             })
-            .collect())
+            .collect();
+
+        if paths.is_empty() && whole_account {
+            paths.push(bookmark.title.clone());
+        }
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// The id of the folder at the given path, where the empty path is the root folder
+    fn folder_id(folders: &[NcFolder], root: i32, parts: &[&str]) -> Option<i32> {
+        match parts.split_first() {
+            None => Some(root),
+            Some((head, rest)) => folders
+                .iter()
+                .find(|folder| &folder.title == head)
+                .and_then(|folder| match rest {
+                    [] => Some(folder.id),
+                    _ => NextcloudStorage::folder_id(&folder.children, folder.id, rest),
+                }),
+        }
     }
 
     fn folder_path(folders: &Vec<NcFolder>, id: i32) -> Option<String> {
@@ -915,3 +3966,262 @@ impl NextcloudStorage {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(bookmarks: &[(&str, &str)]) -> Snapshot {
+        Snapshot {
+            at: 0,
+            bookmarks: bookmarks
+                .iter()
+                .map(|(name, url)| SBookmark {
+                    name: name.to_string(),
+                    url: url.to_string(),
+                    lastmodified: 0,
+                })
+                .collect(),
+            revision_a: None,
+            revision_b: None,
+            root_a: None,
+            root_b: None,
+        }
+    }
+
+    fn bookmarks(bookmarks: &[(&str, &str)]) -> Vec<Bookmark> {
+        bookmarks
+            .iter()
+            .map(|(name, url)| Bookmark {
+                id: 0,
+                name: name.to_string(),
+                url: url.to_string(),
+                lastmodified: 0,
+            })
+            .collect()
+    }
+
+    /// The changes of a side which lists `current`, after detecting renames
+    fn renames(before: &[(&str, &str)], current: &[(&str, &str)]) -> Changes {
+        let snapshot = snapshot(before);
+        let mut changes = Pair::compare_to_snapshot(bookmarks(current), &snapshot);
+        Pair::detect_renames(&mut changes, &Changes::default(), &snapshot);
+        changes
+    }
+
+    fn sorted_keys<T>(map: &HashMap<String, T>) -> Vec<&str> {
+        let mut keys: Vec<&str> = map.keys().map(String::as_str).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn rename_of_a_folder() {
+        let changes = renames(
+            &[
+                ("dev/a", "http://a"),
+                ("dev/b", "http://b"),
+                ("c", "http://c"),
+            ],
+            &[
+                ("work/a", "http://a"),
+                ("work/b", "http://b"),
+                ("c", "http://c"),
+            ],
+        );
+        assert_eq!(changes.renamed, vec![("dev".to_owned(), "work".to_owned())]);
+        assert!(changes.new.is_empty());
+        assert!(changes.deleted.is_empty());
+    }
+
+    #[test]
+    fn partial_move_is_no_rename() {
+        let changes = renames(
+            &[("dev/a", "http://a"), ("dev/b", "http://b")],
+            &[("work/a", "http://a"), ("dev/b", "http://b")],
+        );
+        assert!(changes.renamed.is_empty());
+        assert_eq!(sorted_keys(&changes.new), vec!["work/a"]);
+        assert_eq!(sorted_keys(&changes.deleted), vec!["dev/a"]);
+    }
+
+    #[test]
+    fn rename_of_an_outer_folder_covers_the_inner_folders() {
+        let changes = renames(
+            &[
+                ("dev/x/a", "http://a"),
+                ("dev/x/b", "http://b"),
+                ("dev/c", "http://c"),
+            ],
+            &[
+                ("work/x/a", "http://a"),
+                ("work/x/b", "http://b"),
+                ("work/c", "http://c"),
+            ],
+        );
+        assert_eq!(changes.renamed, vec![("dev".to_owned(), "work".to_owned())]);
+        assert!(changes.new.is_empty());
+        assert!(changes.deleted.is_empty());
+    }
+
+    #[test]
+    fn rename_of_an_inner_folder() {
+        let changes = renames(
+            &[
+                ("dev/x/a", "http://a"),
+                ("dev/x/b", "http://b"),
+                ("dev/c", "http://c"),
+            ],
+            &[
+                ("dev/y/a", "http://a"),
+                ("dev/y/b", "http://b"),
+                ("dev/c", "http://c"),
+            ],
+        );
+        assert_eq!(
+            changes.renamed,
+            vec![("dev/x".to_owned(), "dev/y".to_owned())]
+        );
+        assert!(changes.new.is_empty());
+        assert!(changes.deleted.is_empty());
+    }
+
+    #[test]
+    fn move_into_a_subfolder_is_no_rename() {
+        let changes = renames(&[("dev/x", "http://x")], &[("dev/sub/x", "http://x")]);
+        assert!(changes.renamed.is_empty());
+        assert_eq!(sorted_keys(&changes.new), vec!["dev/sub/x"]);
+        assert_eq!(sorted_keys(&changes.deleted), vec!["dev/x"]);
+
+        let changes = renames(&[("dev/sub/x", "http://x")], &[("dev/x", "http://x")]);
+        assert!(changes.renamed.is_empty());
+    }
+
+    #[test]
+    fn rename_touched_by_the_other_side_is_no_rename() {
+        let snapshot = snapshot(&[("dev/a", "http://a")]);
+        let mut changes =
+            Pair::compare_to_snapshot(bookmarks(&[("work/a", "http://a")]), &snapshot);
+        let other = Pair::compare_to_snapshot(bookmarks(&[("dev/a", "http://b")]), &snapshot);
+        Pair::detect_renames(&mut changes, &other, &snapshot);
+        assert!(changes.renamed.is_empty());
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        assert_eq!(FileStorage::timestamp(0), "19700101T000000.000Z");
+        assert_eq!(FileStorage::timestamp(951782400000), "20000229T000000.000Z");
+        assert_eq!(
+            FileStorage::timestamp(1706745599999),
+            "20240131T235959.999Z"
+        );
+        assert_eq!(
+            FileStorage::timestamp(4107501296007),
+            "21000228T123456.007Z"
+        );
+        assert_eq!(
+            FileStorage::timestamp(4107542400000),
+            "21000301T000000.000Z"
+        );
+    }
+
+    #[test]
+    fn timestamps_are_recognized() {
+        assert!(FileStorage::is_timestamp(&FileStorage::timestamp(
+            1706745599999
+        )));
+        assert!(FileStorage::is_timestamp("20240131T235959.999Z"));
+        assert!(!FileStorage::is_timestamp("20240131T235959.999"));
+        assert!(!FileStorage::is_timestamp("20240131T235959Z"));
+        assert!(!FileStorage::is_timestamp("2024-01-31T23:59:59.999Z"));
+        assert!(!FileStorage::is_timestamp("20240131T23595a.999Z"));
+        assert!(!FileStorage::is_timestamp("20240131"));
+        assert!(!FileStorage::is_timestamp(""));
+    }
+
+    fn line_format(fields: &[&str], separator: Option<&str>) -> LineFormat {
+        LineFormat {
+            fields: fields.iter().map(|field| field.to_string()).collect(),
+            separator: separator.map(str::to_owned),
+        }
+    }
+
+    /// The name and url of a line
+    fn parse<'a>(format: &LineFormat, line: &'a str) -> (&'a str, &'a str) {
+        let (name, url) = format.spans(line).unwrap();
+        (&line[name], &line[url])
+    }
+
+    #[test]
+    fn names_may_contain_the_separator() {
+        let quickmarks = line_format(&["name", "url"], None);
+        assert_eq!(
+            parse(&quickmarks, "my  dev docs http://a"),
+            ("my  dev docs", "http://a")
+        );
+
+        let tsv = line_format(&["name", "url"], Some("\t"));
+        assert_eq!(parse(&tsv, "a\tb\thttp://a"), ("a\tb", "http://a"));
+
+        let custom = line_format(&["name", "tags", "url"], Some(" | "));
+        assert_eq!(
+            parse(&custom, "a | b | rust | http://a"),
+            ("a | b", "http://a")
+        );
+    }
+
+    #[test]
+    fn urls_come_before_the_name() {
+        let urls = line_format(&["url", "name"], None);
+        assert_eq!(
+            parse(&urls, "http://a The  Rust Book"),
+            ("The  Rust Book", "http://a")
+        );
+        assert_eq!(
+            urls.write("The Rust Book", "http://a"),
+            "http://a The Rust Book"
+        );
+    }
+
+    #[test]
+    fn optional_fields_may_be_left_out_at_the_end() {
+        let vimb = line_format(&["url", "name", "tags?"], Some("\t"));
+        assert_eq!(parse(&vimb, "http://a\tdocs"), ("docs", "http://a"));
+        assert_eq!(parse(&vimb, "http://a\tdocs\trust"), ("docs", "http://a"));
+        assert_eq!(
+            parse(&vimb, "http://a\tmy\tdocs\trust"),
+            ("my\tdocs", "http://a")
+        );
+        assert!(vimb.spans("http://a").is_err());
+        assert_eq!(vimb.write("docs", "http://a"), "http://a\tdocs");
+    }
+
+    #[test]
+    fn new_lines_leave_the_other_fields_empty() {
+        let custom = line_format(&["name", "tags", "url", "note?"], Some("|"));
+        assert_eq!(custom.write("docs", "http://a"), "docs||http://a");
+        assert_eq!(parse(&custom, "docs||http://a"), ("docs", "http://a"));
+    }
+
+    #[test]
+    fn replace_keeps_the_other_fields() {
+        let vimb = line_format(&["url", "name", "tags?"], Some("\t"));
+        assert_eq!(
+            vimb.replace("http://a\tdocs\trust,book", "my\tdocs", "http://b"),
+            "http://b\tmy\tdocs\trust,book"
+        );
+
+        let custom = line_format(&["name", "tags", "url"], Some(" | "));
+        assert_eq!(
+            custom.replace("a | b | rust | http://a", "c", "http://c"),
+            "c | rust | http://c"
+        );
+
+        // Whitespace between the fields stays as it is
+        let quickmarks = line_format(&["name", "url"], None);
+        assert_eq!(
+            quickmarks.replace("docs\t  http://a", "my docs", "http://b"),
+            "my docs\t  http://b"
+        );
+    }
+}